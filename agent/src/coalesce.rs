@@ -0,0 +1,85 @@
+use tokio::sync::broadcast::{ self, error::RecvError };
+use tokio::time::{ timeout_at, Duration, Instant };
+
+/// How shell output is merged into MQTT messages.
+#[derive(Clone, Copy, Debug)]
+pub struct Coalescing {
+    /// Shortest time between two messages while output keeps coming.
+    pub delay: Duration,
+    /// Largest message.
+    pub max_bytes: usize,
+}
+
+/// Merges output chunks into fewer, larger messages. After a quiet period
+/// the first chunk goes out at once, so echo is not delayed; while output
+/// keeps coming, chunks are collected until `delay` has passed since the
+/// previous message or `max_bytes` are pending.
+pub struct Coalescer {
+    receiver: broadcast::Receiver<Vec<u8>>,
+    options: Coalescing,
+    pending: Vec<u8>,
+    last: Option<Instant>,
+    lagged: bool,
+}
+
+impl Coalescer {
+    pub fn new(receiver: broadcast::Receiver<Vec<u8>>, options: Coalescing) -> Self {
+        Self {
+            receiver,
+            options: Coalescing { max_bytes: options.max_bytes.max(1), ..options },
+            pending: Vec::new(),
+            last: None,
+            lagged: false,
+        }
+    }
+
+    /// Whether output was lost since the last call, in which case the
+    /// controller's screen needs a redraw.
+    pub fn take_lagged(&mut self) -> bool {
+        std::mem::take(&mut self.lagged)
+    }
+
+    /// Next message to publish, or `None` once the output channel closed.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            let chunk = self.recv().await?;
+            self.pending = chunk;
+        }
+        let deadline = self.last.map_or(Instant::now(), |last| last + self.options.delay);
+        while self.pending.len() < self.options.max_bytes {
+            // Whatever is already queued goes along, even after the deadline
+            let chunk = match self.receiver.try_recv() {
+                Ok(chunk) => chunk,
+                Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                    self.lost(skipped);
+                    continue;
+                }
+                Err(_) => {
+                    match timeout_at(deadline, self.recv()).await {
+                        Ok(Some(chunk)) => chunk,
+                        Ok(None) | Err(_) => break,
+                    }
+                }
+            };
+            self.pending.extend(chunk);
+        }
+        self.last = Some(Instant::now());
+        let rest = self.pending.split_off(self.pending.len().min(self.options.max_bytes));
+        Some(std::mem::replace(&mut self.pending, rest))
+    }
+
+    async fn recv(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(chunk) => return Some(chunk),
+                Err(RecvError::Lagged(skipped)) => self.lost(skipped),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    fn lost(&mut self, skipped: u64) {
+        eprintln!("⚠️  Output publisher lagged, {} chunks lost", skipped);
+        self.lagged = true;
+    }
+}
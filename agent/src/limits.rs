@@ -0,0 +1,314 @@
+use nix::sys::resource::{ self, Resource };
+use nix::sys::signal::Signal;
+use nix::sys::wait::{ self, Id, WaitPidFlag, WaitStatus };
+use nix::unistd::{ self, Pid, SysconfVar };
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Counters in the session cgroup that tell a limit was hit, per file.
+const EVENTS: [(&str, &[&str]); 2] = [
+    ("memory", &["max", "oom", "oom_kill"]),
+    ("pids", &["max"]),
+];
+
+/// Numbers the session cgroups of this agent.
+static SESSIONS: AtomicU32 = AtomicU32::new(0);
+
+/// A resource limited for every process of a session with setrlimit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// CPU seconds; the process gets SIGXCPU when it runs out.
+    Cpu,
+    /// Address space in bytes; allocations beyond it fail.
+    Memory,
+    /// Open file descriptors.
+    Files,
+    /// Processes of the session's user, not enforced for root.
+    Processes,
+}
+
+impl Limit {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Limit::Cpu => "cpu",
+            Limit::Memory => "memory",
+            Limit::Files => "files",
+            Limit::Processes => "processes",
+        }
+    }
+
+    /// Apply to the calling process and what it starts. The hard limit is
+    /// set as well, so the session cannot raise it again.
+    pub fn apply(&self, value: u64) -> nix::Result<()> {
+        let resource = match self {
+            Limit::Cpu => Resource::RLIMIT_CPU,
+            Limit::Memory => Resource::RLIMIT_AS,
+            Limit::Files => Resource::RLIMIT_NOFILE,
+            Limit::Processes => Resource::RLIMIT_NPROC,
+        };
+        // A second past the soft CPU limit the kernel sends SIGKILL
+        // instead, giving the process the chance to report SIGXCPU first
+        let hard = if *self == Limit::Cpu { value.saturating_add(1) } else { value };
+        resource::setrlimit(resource, value, hard)
+    }
+}
+
+impl FromStr for Limit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu" => Ok(Limit::Cpu),
+            "memory" => Ok(Limit::Memory),
+            "files" => Ok(Limit::Files),
+            "processes" => Ok(Limit::Processes),
+            _ => anyhow::bail!("unknown limit '{}', expected cpu, memory, files or processes", s),
+        }
+    }
+}
+
+/// Parse `RESOURCE=VALUE`, as passed to the `exec` subcommand.
+pub fn parse_rlimit(spec: &str) -> anyhow::Result<(Limit, u64)> {
+    let Some((limit, value)) = spec.split_once('=') else {
+        anyhow::bail!("expected RESOURCE=VALUE, got '{}'", spec);
+    };
+    Ok((limit.parse()?, value.parse()?))
+}
+
+/// Parse a byte count with an optional K, M or G suffix.
+fn parse_size(spec: &str) -> anyhow::Result<u64> {
+    let (digits, unit) = match spec.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&spec[..at], 1 << 10),
+        Some((at, 'M' | 'm')) => (&spec[..at], 1 << 20),
+        Some((at, 'G' | 'g')) => (&spec[..at], 1 << 30),
+        _ => (spec, 1),
+    };
+    let value: u64 = digits.parse().map_err(|_| anyhow::anyhow!("expected a size like 512M, got '{}'", spec))?;
+    Ok(value.saturating_mul(unit))
+}
+
+fn parse_setting(spec: &str) -> anyhow::Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((file, value)) if file.contains('.') && !file.contains('/') => Ok((file.to_string(), value.to_string())),
+        _ => anyhow::bail!("expected FILE=VALUE such as memory.max=256M, got '{}'", spec),
+    }
+}
+
+/// Resource limits of sessions.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LimitOptions {
+    /// CPU seconds each process of a session may use
+    #[arg(long, value_name = "SECS")]
+    limit_cpu: Option<u64>,
+
+    /// Address space of each process of a session, e.g. 512M
+    #[arg(long, value_name = "BYTES", value_parser = parse_size)]
+    limit_memory: Option<u64>,
+
+    /// Files each process of a session may have open
+    #[arg(long, value_name = "COUNT")]
+    limit_files: Option<u64>,
+
+    /// Processes the session's user may run, counting all of the user's
+    /// processes. Not enforced for root
+    #[arg(long, value_name = "COUNT")]
+    limit_processes: Option<u64>,
+
+    /// cgroup v2 directory, e.g. /sys/fs/cgroup/mqttshell, in which each
+    /// session gets a cgroup of its own. Created if missing
+    #[arg(long, value_name = "DIR")]
+    cgroup: Option<PathBuf>,
+
+    /// Interface file to write in every session cgroup, e.g.
+    /// memory.max=256M, pids.max=64 or "cpu.max=50000 100000". Can be
+    /// repeated
+    #[arg(long = "cgroup-set", value_name = "FILE=VALUE", value_parser = parse_setting, requires = "cgroup")]
+    cgroup_settings: Vec<(String, String)>,
+}
+
+impl LimitOptions {
+    /// The rlimits every session process starts with.
+    pub fn rlimits(&self) -> Vec<(Limit, u64)> {
+        [
+            (Limit::Cpu, self.limit_cpu),
+            (Limit::Memory, self.limit_memory),
+            (Limit::Files, self.limit_files),
+            (Limit::Processes, self.limit_processes),
+        ]
+            .into_iter()
+            .filter_map(|(limit, value)| Some((limit, value?)))
+            .collect()
+    }
+
+    /// Create the cgroup for a new session and write the settings into it,
+    /// if sessions are confined to cgroups.
+    pub async fn create_cgroup(&self) -> anyhow::Result<Option<PathBuf>> {
+        let Some(base) = &self.cgroup else {
+            return Ok(None);
+        };
+        tokio::fs::create_dir_all(base).await?;
+        // Controllers have to be enabled for the children of `base` before
+        // their interface files show up there
+        let mut controllers: Vec<&str> = EVENTS.iter().map(|(controller, _)| *controller).collect();
+        for (file, _) in &self.cgroup_settings {
+            let controller = file.split('.').next().unwrap_or_default();
+            if !controllers.contains(&controller) && controller != "cgroup" {
+                controllers.push(controller);
+            }
+        }
+        for controller in controllers {
+            if let Err(e) = tokio::fs::write(base.join("cgroup.subtree_control"), format!("+{}", controller)).await {
+                eprintln!("⚠️  Cannot enable the {} controller in {}: {}", controller, base.display(), e);
+            }
+        }
+        let session = SESSIONS.fetch_add(1, Ordering::Relaxed);
+        let dir = base.join(format!("session-{}-{}", std::process::id(), session));
+        tokio::fs::create_dir(&dir).await?;
+        for (file, value) in &self.cgroup_settings {
+            if let Err(e) = tokio::fs::write(dir.join(file), value).await {
+                let _ = tokio::fs::remove_dir(&dir).await;
+                anyhow::bail!("cannot set {} to '{}': {}", file, value, e);
+            }
+        }
+        println!("📦 Session cgroup {}", dir.display());
+        Ok(Some(dir))
+    }
+}
+
+/// Remove a session cgroup once its shell exited, killing what the session
+/// left running.
+pub async fn remove_cgroup(dir: &Path) {
+    let _ = tokio::fs::write(dir.join("cgroup.kill"), "1").await;
+    for _ in 0..20 {
+        if tokio::fs::remove_dir(dir).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    eprintln!("⚠️  Cannot remove session cgroup {}", dir.display());
+}
+
+/// Counters of `<controller>.events` in `dir`.
+async fn read_events(dir: &Path, controller: &str) -> BTreeMap<String, u64> {
+    let events = tokio::fs::read_to_string(dir.join(format!("{}.events", controller))).await.unwrap_or_default();
+    events
+        .lines()
+        .filter_map(|line| {
+            let (key, count) = line.split_once(' ')?;
+            Some((key.to_string(), count.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Publish `limit_exceeded <controller> <event> <count>` on the status
+/// topic whenever the session cgroup `dir` records hitting a limit, e.g.
+/// `limit_exceeded memory oom_kill 1`. Polled, like the echo mode.
+pub async fn watch(dir: PathBuf, status_tx: broadcast::Sender<String>) {
+    let mut ticks = tokio::time::interval(Duration::from_millis(500));
+    let mut seen: BTreeMap<(&str, String), u64> = BTreeMap::new();
+    loop {
+        ticks.tick().await;
+        for (controller, keys) in EVENTS {
+            for (key, count) in read_events(&dir, controller).await {
+                if !keys.contains(&key.as_str()) {
+                    continue;
+                }
+                let previous = seen.insert((controller, key.clone()), count).unwrap_or(0);
+                if count > previous {
+                    let _ = status_tx.send(format!("limit_exceeded {} {} {}", controller, key, count));
+                }
+            }
+        }
+    }
+}
+
+/// Wait for the child `pid` to exit and reap it, along with the CPU time
+/// it used itself. Blocks.
+///
+/// The CPU time is read from `/proc` while the child is still a zombie:
+/// the rusage of `wait4` also counts the children it reaped, which the CPU
+/// rlimit does not.
+pub fn reap(pid: Pid) -> nix::Result<(WaitStatus, Option<Duration>)> {
+    wait::waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT)?;
+    let cpu_time = cpu_time(pid);
+    Ok((wait::waitpid(pid, None)?, cpu_time))
+}
+
+/// User and system time of `pid`, without its children.
+fn cpu_time(pid: Pid) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may contain spaces; utime and stime
+    // are the 14th and 15th fields
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let ticks: u64 = fields.next()?.parse::<u64>().ok()? + fields.next()?.parse::<u64>().ok()?;
+    let per_second = unistd::sysconf(SysconfVar::CLK_TCK).ok()??;
+    Some(Duration::from_secs_f64((ticks as f64) / (per_second as f64)))
+}
+
+/// The rlimit that ended the session's process, as a status like
+/// `limit_exceeded cpu SIGXCPU`. The kernel sends SIGXCPU at the soft CPU
+/// limit and SIGKILL at the hard one; other rlimits make system calls fail
+/// instead, which only the session sees.
+///
+/// A SIGKILL only counts when `cpu_time`, the process's own, reached the
+/// limit: OOM kills and `kill -9` end the process the same way.
+pub fn exceeded(status: &WaitStatus, cpu_time: Option<Duration>, rlimits: &[(Limit, u64)]) -> Option<String> {
+    let WaitStatus::Signaled(_, signal, _) = status else {
+        return None;
+    };
+    let cpu_limit = rlimits
+        .iter()
+        .find(|(limit, _)| *limit == Limit::Cpu)
+        .map(|(_, seconds)| Duration::from_secs(*seconds));
+    match (signal, cpu_limit, cpu_time) {
+        (Signal::SIGXCPU, _, _) => {}
+        (Signal::SIGKILL, Some(limit), Some(used)) if used >= limit => {}
+        _ => {
+            return None;
+        }
+    }
+    Some(format!("limit_exceeded {} {}", Limit::Cpu.name(), signal.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn killed(signal: Signal) -> WaitStatus {
+        WaitStatus::Signaled(Pid::from_raw(1), signal, false)
+    }
+
+    #[test]
+    fn sigkill_is_put_down_to_the_cpu_limit_only_once_it_is_used_up() {
+        let rlimits = [(Limit::Cpu, 2)];
+        let used = Some(Duration::from_millis(3010));
+        assert_eq!(exceeded(&killed(Signal::SIGKILL), used, &rlimits).as_deref(), Some("limit_exceeded cpu SIGKILL"));
+        // An OOM kill or kill -9 before the limit was reached
+        assert_eq!(exceeded(&killed(Signal::SIGKILL), Some(Duration::from_millis(400)), &rlimits), None);
+        assert_eq!(exceeded(&killed(Signal::SIGKILL), None, &rlimits), None);
+        assert_eq!(exceeded(&killed(Signal::SIGKILL), used, &[(Limit::Files, 64)]), None);
+    }
+
+    #[test]
+    fn sigxcpu_and_exits() {
+        assert_eq!(
+            exceeded(&killed(Signal::SIGXCPU), None, &[(Limit::Cpu, 2)]).as_deref(),
+            Some("limit_exceeded cpu SIGXCPU")
+        );
+        assert_eq!(exceeded(&killed(Signal::SIGTERM), None, &[(Limit::Cpu, 2)]), None);
+        assert_eq!(exceeded(&WaitStatus::Exited(Pid::from_raw(1), 0), None, &[(Limit::Cpu, 2)]), None);
+    }
+
+    #[test]
+    fn cpu_time_of_a_reaped_child() {
+        // Reaped by `reap` rather than by std
+        let pid = std::process::Command::new("true").spawn().unwrap().id();
+        let (status, cpu_time) = reap(Pid::from_raw(pid as i32)).unwrap();
+        assert!(matches!(status, WaitStatus::Exited(_, 0)));
+        assert!(cpu_time.unwrap() < Duration::from_secs(1));
+    }
+}
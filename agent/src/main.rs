@@ -1,12 +1,33 @@
-use portable_pty::{ native_pty_system, CommandBuilder, PtyPair, PtySize };
-use tokio::sync::broadcast;
+use portable_pty::{ native_pty_system, ChildKiller, PtyPair, PtySize };
+use tokio::sync::{ broadcast, mpsc };
 use rumqttc::{ AsyncClient, MqttOptions, QoS };
-use serde::{ Deserialize, Serialize };
+use nix::sys::termios::{ tcgetattr, LocalFlags };
+use nix::unistd::Pid;
 use std::io::{ Read, Write };
+use std::os::fd::{ AsRawFd, BorrowedFd, OwnedFd, RawFd };
 use std::sync::{ Arc, Mutex };
 use std::time::Duration;
 use std::thread;
-use clap::Parser;
+use clap::{ Parser, Subcommand };
+use mqttshell_proto::compress::{ self, Algorithm, Opener, Sealer };
+use mqttshell_proto::envelope::{ Body, Format, Kind, Wire };
+use mqttshell_proto::handshake::Capability;
+
+mod coalesce;
+mod limits;
+mod privileges;
+mod screen;
+mod session;
+mod shell;
+mod streams;
+
+use coalesce::{ Coalescer, Coalescing };
+use limits::{ Limit, LimitOptions };
+use privileges::Launch;
+use screen::ScreenSync;
+use session::Offer;
+use shell::ShellOptions;
+use streams::Streams;
 
 #[derive(Parser, Debug)]
 #[command(name = "mqtt-shell-agent")]
@@ -20,18 +41,81 @@ struct Args {
 
     #[arg(long, default_value_t = 1883)]
     port: u16,
+
+    /// Unix socket controllers may forward to; a path ending in '/' allows
+    /// every socket in that directory. Can be repeated.
+    #[arg(long = "allow-unix-socket", value_name = "PATH")]
+    allow_unix_socket: Vec<std::path::PathBuf>,
+
+    /// Directory controllers may upload files into. Uploads are refused
+    /// without it.
+    #[arg(long, value_name = "DIR")]
+    upload_dir: Option<std::path::PathBuf>,
+
+    /// Publish diffs of the terminal screen instead of the raw output, so
+    /// bandwidth follows what changes on screen rather than output volume
+    #[arg(long)]
+    screen_sync: bool,
+
+    /// Shortest time between two output messages while output keeps
+    /// coming; output after a quiet period is sent at once
+    #[arg(long, default_value_t = 5, value_name = "MS")]
+    coalesce_ms: u64,
+
+    /// Largest output message. Keep it under the controller's 10 KiB
+    /// packet limit
+    #[arg(long, default_value_t = 8192, value_name = "BYTES")]
+    coalesce_bytes: usize,
+
+    /// Most screen frames published per second with --screen-sync
+    #[arg(long, default_value_t = 10, value_name = "FPS")]
+    max_fps: u32,
+
+    /// Decline the payload compression offered by controllers
+    #[arg(long)]
+    no_compression: bool,
+
+    /// Payload encoding on every topic; controllers must use the same
+    #[arg(long, value_enum, default_value_t = Format::Raw)]
+    format: Format,
+
+    #[command(flatten)]
+    shell: ShellOptions,
+
+    #[command(flatten)]
+    limits: LimitOptions,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TerminalResize {
-    rows: u16,
-    cols: u16,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Set up the session process and run COMMAND; how the agent starts
+    /// sessions as another user or with limits
+    #[command(hide = true)]
+    Exec {
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long = "rlimit", value_name = "RESOURCE=VALUE", value_parser = limits::parse_rlimit)]
+        rlimits: Vec<(Limit, u64)>,
+        #[arg(long)]
+        cgroup: Option<std::path::PathBuf>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Exec { user, rlimits, cgroup, command }) = &args.command {
+        let Err(e) = privileges::exec(user.as_deref(), rlimits, cgroup.as_deref(), command);
+        eprintln!("❌ Cannot start the session: {:#}", e);
+        std::process::exit(1);
+    }
+
     println!("🚀 Starting MQTT Shell Agent with auto-reconnect and shell restart...");
     println!("📡 Using channel: '{}' on {}:{}", args.channel, args.host, args.port);
 
@@ -39,39 +123,106 @@ async fn main() -> anyhow::Result<()> {
     let topic_out = format!("{}/out", args.channel);
     let topic_status = format!("{}/status", args.channel);
     let topic_resize = format!("{}/resize", args.channel);
+    let topic_ctl = format!("{}/ctl", args.channel);
+    let topic_hello = format!("{}/hello", args.channel);
+    let topic_welcome = format!("{}/welcome", args.channel);
+    let mut capabilities = vec![Capability::Forwarding, Capability::Session];
+    if !args.no_compression {
+        capabilities.push(Capability::Compression);
+    }
+    if args.screen_sync {
+        capabilities.push(Capability::Snapshot);
+    }
+    let offer = Offer {
+        capabilities,
+        profiles: args.shell.profile_names(),
+        users: args.shell.user_names(),
+    };
+    if args.shell.switches_user() && !nix::unistd::geteuid().is_root() {
+        println!("⚠️  Not running as root, sessions can only run as the agent's own user");
+    }
+    let wire = Arc::new(Wire::new(args.format));
+    let streams = Streams::new(
+        &args.channel,
+        args.allow_unix_socket.clone(),
+        args.upload_dir.clone(),
+        Arc::clone(&wire)
+    );
+    // Streams keep their own broker connection across sessions
+    tokio::spawn(streams.serve(format!("agent-streams-{}", args.channel), (args.host.clone(), args.port)));
 
     loop {
+        wire.new_session();
         let (output_tx, _) = broadcast::channel::<Vec<u8>>(1000);
         let (status_tx, _) = broadcast::channel::<String>(10);
         let (input_tx, input_rx) = std::sync::mpsc::channel::<Vec<u8>>();
         let input_rx = Arc::new(Mutex::new(input_rx));
+        let open = session::wait_for_open(
+            &args.channel,
+            (&args.host, args.port),
+            (&wire, &offer),
+            &args.shell
+        ).await;
+        let launch = async {
+            let user = args.shell.account(&open)?;
+            let cgroup = args.limits.create_cgroup().await?;
+            anyhow::Ok(Launch { user, rlimits: args.limits.rlimits(), cgroup })
+        }.await;
+        let launch = match launch {
+            Ok(launch) => launch,
+            Err(e) => {
+                eprintln!("❌ Cannot run the session: {:#}", e);
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                continue;
+            }
+        };
         println!("🔄 Creating new shell instance...");
 
         let pty_system = native_pty_system();
         let pty_pair = pty_system
             .openpty(PtySize {
-                rows: 24,
-                cols: 80,
-                pixel_width: 0,
-                pixel_height: 0,
+                rows: open.size.rows,
+                cols: open.size.cols,
+                pixel_width: open.size.pixel_width,
+                pixel_height: open.size.pixel_height,
             })
             .expect("Failed to open pty");
 
-        let mut cmd = CommandBuilder::new("/bin/bash");
-        cmd.arg("-i");
-        cmd.env("TERM", "xterm-256color");
-        cmd.env("COLORTERM", "truecolor");
-
-        let mut child = pty_pair.slave.spawn_command(cmd).expect("Failed to spawn shell");
+        let spawned = args.shell
+            .command(&open, &launch)
+            .and_then(|cmd| pty_pair.slave.spawn_command(cmd));
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("❌ Failed to spawn shell: {:?}", e);
+                if let Some(cgroup) = &launch.cgroup {
+                    limits::remove_cgroup(cgroup).await;
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                continue;
+            }
+        };
+        let killer = child.clone_killer();
 
         println!("✅ Shell started in PTY");
 
         let reader = pty_pair.master.try_clone_reader().expect("Failed to clone reader");
+        // SAFETY: the master is open, it is only dropped when the shell exits
+        let termios_fd = pty_pair.master
+            .as_raw_fd()
+            .and_then(|fd| unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned().ok());
         let writer = pty_pair.master.take_writer().expect("Failed to get writer");
         let writer = Arc::new(Mutex::new(writer));
 
         let _ = status_tx.send("shell_ready".to_string());
 
+        let screen = args.screen_sync.then(|| ScreenSync::new(open.size.rows, open.size.cols));
+        let frame_task = screen.clone().map(|screen| {
+            let interval = Duration::from_secs(1) / args.max_fps.max(1);
+            tokio::spawn(screen.run(output_tx.clone(), interval))
+        });
+
+        let screen_feed = screen.clone();
         let output_broadcaster = output_tx.clone();
         let status_broadcaster = status_tx.clone();
         let _ = thread::spawn(move || {
@@ -89,7 +240,12 @@ async fn main() -> anyhow::Result<()> {
                         break;
                     }
                     Ok(n) => {
-                        let _ = output_broadcaster.send(buf[..n].to_vec());
+                        match &screen_feed {
+                            Some(screen) => screen.feed(&buf[..n]),
+                            None => {
+                                let _ = output_broadcaster.send(buf[..n].to_vec());
+                            }
+                        }
                     }
                     Err(e) => {
                         eprintln!("❌ Error reading PTY: {:?}", e);
@@ -100,6 +256,9 @@ async fn main() -> anyhow::Result<()> {
             }
         });
 
+        let echo_task = termios_fd.map(|fd| tokio::spawn(watch_echo(fd, status_tx.clone())));
+        let limits_task = launch.cgroup.clone().map(|cgroup| tokio::spawn(limits::watch(cgroup, status_tx.clone())));
+
         let writer_clone = Arc::clone(&writer);
         let input_rx_clone = Arc::clone(&input_rx);
         let _writer_handle = thread::spawn(move || {
@@ -139,18 +298,54 @@ async fn main() -> anyhow::Result<()> {
                 topic_out.clone(),
                 topic_status.clone(),
                 topic_resize.clone(),
+                topic_ctl.clone(),
+                topic_hello.clone(),
+                topic_welcome.clone(),
             );
-            let host = args.host.clone();
-            let port = args.port;
+            let protocol = (Arc::clone(&wire), offer.clone());
+            let broker = (args.host.clone(), args.port);
+            let coalescing = Coalescing {
+                delay: Duration::from_millis(args.coalesce_ms),
+                max_bytes: args.coalesce_bytes,
+            };
             async move {
-                mqtt_shell_loop(output_tx, status_tx, input_tx, pty_pair, topics, host, port).await;
+                mqtt_shell_loop(
+                    (output_tx, coalescing),
+                    status_tx,
+                    input_tx,
+                    (pty_pair, killer, screen),
+                    protocol,
+                    topics,
+                    broker
+                ).await;
             }
         });
 
-        let _ = child.wait();
+        // Reaped here rather than by portable_pty, which only keeps the
+        // name of the signal that ended the process
+        let pid = child.process_id().map(|pid| Pid::from_raw(pid as i32));
+        let status = match pid {
+            Some(pid) => tokio::task::spawn_blocking(move || limits::reap(pid)).await.ok().and_then(Result::ok),
+            None => {
+                let _ = child.wait();
+                None
+            }
+        };
+        if let Some(exceeded) = status.and_then(|(status, cpu_time)| limits::exceeded(&status, cpu_time, &launch.rlimits)) {
+            println!("🚫 Session ended by its resource limits: {}", exceeded);
+            let _ = status_tx.send(exceeded);
+            // Gives the MQTT task the chance to publish it
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
         println!("🔄 Shell exited, restarting in 2 seconds...");
 
         mqtt_task.abort();
+        for task in [echo_task, frame_task, limits_task].into_iter().flatten() {
+            task.abort();
+        }
+        if let Some(cgroup) = &launch.cgroup {
+            limits::remove_cgroup(cgroup).await;
+        }
 
         tokio::time::sleep(Duration::from_secs(2)).await;
     }
@@ -159,16 +354,92 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// `echo_on` while typed characters show up on screen, either echoed by
+/// the line discipline or drawn by an application reading raw input, and
+/// `echo_off` at password prompts and the like.
+fn echo_status(fd: RawFd) -> Option<&'static str> {
+    let flags = tcgetattr(fd).ok()?.local_flags;
+    let echoes = flags.contains(LocalFlags::ECHO) || !flags.contains(LocalFlags::ICANON);
+    Some(if echoes { "echo_on" } else { "echo_off" })
+}
+
+/// Publish the echo mode on the status topic whenever it changes, so
+/// controllers stop predicting echo at password prompts. Polled, since
+/// nothing is printed after `read -s` turns echo off.
+async fn watch_echo(fd: OwnedFd, status_tx: broadcast::Sender<String>) {
+    let mut ticks = tokio::time::interval(Duration::from_millis(50));
+    let mut echo = None;
+    loop {
+        ticks.tick().await;
+        let current = echo_status(fd.as_raw_fd());
+        if let Some(status) = current.filter(|_| current != echo) {
+            echo = current;
+            let _ = status_tx.send(status.to_string());
+        }
+    }
+}
+
+/// First half of a screen refresh: make the PTY one column narrower, so
+/// that restoring the returned size shortly after delivers a SIGWINCH the
+/// application answers by redrawing.
+fn shrink_for_refresh(master: &dyn portable_pty::MasterPty) -> Option<PtySize> {
+    let size = master.get_size().ok()?;
+    let narrower = PtySize {
+        cols: size.cols.saturating_sub(1).max(1),
+        ..size
+    };
+    master.resize(narrower).ok()?;
+    Some(size)
+}
+
+/// Have the controller's screen redrawn: a full frame when the agent keeps
+/// the screen, otherwise a size change the application redraws on.
+async fn redraw(screen: Option<&ScreenSync>, master: &mut (dyn portable_pty::MasterPty + Send)) {
+    if let Some(screen) = screen {
+        screen.request_full();
+    } else if let Some(size) = shrink_for_refresh(master) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _ = master.resize(size);
+    }
+}
+
+/// Answer a compression offer: pick the controller's preferred algorithm
+/// and start a new epoch, so payloads compressed for an earlier offer are
+/// told apart. Returns the decoder for compressed input; the choice goes to
+/// the output publisher, which announces it on the status topic.
+fn negotiate(
+    offered: &[Algorithm],
+    enabled: bool,
+    epoch: &mut u8,
+    codec_tx: &mpsc::UnboundedSender<Option<(Algorithm, u8)>>
+) -> Option<Opener> {
+    *epoch = epoch.wrapping_add(1);
+    let chosen = offered.first().filter(|_| enabled).map(|algorithm| (*algorithm, *epoch));
+    let opener = chosen.and_then(|(algorithm, epoch)| Opener::new(algorithm, epoch).ok());
+    let _ = codec_tx.send(chosen.filter(|_| opener.is_some()));
+    opener
+}
+
 async fn mqtt_shell_loop(
-    output_tx: broadcast::Sender<Vec<u8>>,
+    output: (broadcast::Sender<Vec<u8>>, Coalescing),
     status_tx: broadcast::Sender<String>,
     input_tx: std::sync::mpsc::Sender<Vec<u8>>,
-    pty_master: PtyPair,
-    topics: (String, String, String, String),
-    mqtt_host: String,
-    mqtt_port: u16
+    shell: (PtyPair, Box<dyn ChildKiller + Send + Sync>, Option<Arc<ScreenSync>>),
+    protocol: (Arc<Wire>, Offer),
+    topics: (String, String, String, String, String, String, String),
+    broker: (String, u16)
 ) {
-    let (topic_in, topic_out, topic_status, topic_resize) = topics;
+    let (topic_in, topic_out, topic_status, topic_resize, topic_ctl, topic_hello, topic_welcome) = topics;
+    let (mut pty_master, mut killer, screen) = shell;
+    let (mqtt_host, mqtt_port) = broker;
+    let (output_tx, coalescing) = output;
+    let (wire, offer) = protocol;
+    let compression = offer.capabilities.contains(&Capability::Compression);
+    let topic_in_z = format!("{}/z", topic_in);
+    let topic_out_z = format!("{}/z", topic_out);
+    let mut epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos() as u8);
     let mut reconnect_delay = 1;
 
     loop {
@@ -185,6 +456,13 @@ async fn mqtt_shell_loop(
             continue;
         }
 
+        if let Err(e) = client.subscribe(&topic_in_z, QoS::AtMostOnce).await {
+            eprintln!("❌ Failed to subscribe to {}: {:?}", topic_in_z, e);
+            tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
+            reconnect_delay = std::cmp::min(reconnect_delay * 2, 30);
+            continue;
+        }
+
         if let Err(e) = client.subscribe(&topic_resize, QoS::AtMostOnce).await {
             eprintln!("❌ Failed to subscribe to {}: {:?}", topic_resize, e);
             tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
@@ -192,10 +470,24 @@ async fn mqtt_shell_loop(
             continue;
         }
 
+        if let Err(e) = client.subscribe(&topic_ctl, QoS::AtMostOnce).await {
+            eprintln!("❌ Failed to subscribe to {}: {:?}", topic_ctl, e);
+            tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
+            reconnect_delay = std::cmp::min(reconnect_delay * 2, 30);
+            continue;
+        }
+
+        if let Err(e) = client.subscribe(&topic_hello, QoS::AtMostOnce).await {
+            eprintln!("❌ Failed to subscribe to {}: {:?}", topic_hello, e);
+            tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
+            reconnect_delay = std::cmp::min(reconnect_delay * 2, 30);
+            continue;
+        }
+
         println!("✅ Subscribed to MQTT topics");
         reconnect_delay = 1;
 
-        let mut output_receiver = output_tx.subscribe();
+        let output_receiver = output_tx.subscribe();
         let mut status_receiver = status_tx.subscribe();
         println!(
             "🔗 Broadcast receivers created (output: {}, status: {})",
@@ -204,26 +496,78 @@ async fn mqtt_shell_loop(
         );
         let client_output = client.clone();
         let client_status = client.clone();
+        // Compression starts afresh with every connection, controllers
+        // offer it again when they see agent_online
+        let (codec_tx, mut codec_rx) = mpsc::unbounded_channel::<Option<(Algorithm, u8)>>();
+        let mut input_opener: Option<Opener> = None;
+        // Lost output leaves the controller's screen out of date
+        let (redraw_tx, mut redraw_rx) = mpsc::unbounded_channel::<()>();
 
         let publish_task = tokio::spawn({
+            let wire = Arc::clone(&wire);
             let topic_out = topic_out.clone();
+            let topic_out_z = topic_out_z.clone();
+            let topic_status = topic_status.clone();
             async move {
-                while let Ok(output) = output_receiver.recv().await {
-                    if
-                        let Err(_) = client_output.publish(
-                            &topic_out,
-                            QoS::AtMostOnce,
-                            false,
-                            output
-                        ).await
-                    {
-                        break;
+                let mut coalescer = Coalescer::new(output_receiver, coalescing);
+                let mut sealer: Option<Sealer> = None;
+                let mut logged = 0;
+                loop {
+                    tokio::select! {
+                        biased;
+                        chosen = codec_rx.recv() => {
+                            let Some(chosen) = chosen else {
+                                break;
+                            };
+                            if let Some(sealer) = &sealer {
+                                println!("🗜️  Output compression ratio was {}", sealer.ratio);
+                            }
+                            sealer = chosen.and_then(|(algorithm, epoch)| Sealer::new(algorithm, epoch).ok());
+                            logged = 0;
+                            // Published from here so that it goes out before
+                            // the first compressed output
+                            let status = compress::accept(chosen);
+                            println!("📤 Publishing status: {}", status);
+                            let payload = wire.encode(Body::Status(status));
+                            if client_output.publish(&topic_status, QoS::AtMostOnce, false, payload).await.is_err() {
+                                break;
+                            }
+                        }
+                        output = coalescer.next() => {
+                            let Some(output) = output else {
+                                break;
+                            };
+                            if coalescer.take_lagged() {
+                                let _ = redraw_tx.send(());
+                            }
+                            let published = match &mut sealer {
+                                Some(sealer) => {
+                                    let Ok(payload) = sealer.seal(&output) else {
+                                        break;
+                                    };
+                                    if sealer.ratio.raw >> 20 > logged {
+                                        logged = sealer.ratio.raw >> 20;
+                                        println!("🗜️  Output compression ratio {}", sealer.ratio);
+                                    }
+                                    let payload = wire.encode(Body::Data(payload));
+                                    client_output.publish(&topic_out_z, QoS::AtMostOnce, false, payload).await
+                                }
+                                None => {
+                                    let payload = wire.encode(Body::Data(output));
+                                    client_output.publish(&topic_out, QoS::AtMostOnce, false, payload).await
+                                }
+                            };
+                            if published.is_err() {
+                                break;
+                            }
+                        }
                     }
                 }
             }
         });
 
         let status_task = tokio::spawn({
+            let wire = Arc::clone(&wire);
             let topic_status = topic_status.clone();
             async move {
                 println!("🔍 Status task started");
@@ -234,7 +578,7 @@ async fn mqtt_shell_loop(
                             &topic_status,
                             QoS::AtMostOnce,
                             false,
-                            status.clone()
+                            wire.encode(Body::Status(status.clone()))
                         ).await
                     {
                         Ok(_) => println!("✅ Status '{}' published", status),
@@ -249,33 +593,114 @@ async fn mqtt_shell_loop(
         });
 
         println!("✅ MQTT Agent ready");
+        // Controllers resend what was published while the agent was away
+        let _ = status_tx.send("agent_online".to_string());
+        if let Some(echo) = pty_master.master.as_raw_fd().and_then(echo_status) {
+            let _ = status_tx.send(echo.to_string());
+        }
 
         loop {
-            match eventloop.poll().await {
+            let event = tokio::select! {
+                Some(()) = redraw_rx.recv() => {
+                    while redraw_rx.try_recv().is_ok() {}
+                    println!("🔃 Redrawing the screen after lost output");
+                    redraw(screen.as_deref(), pty_master.master.as_mut()).await;
+                    continue;
+                }
+                event = eventloop.poll() => event,
+            };
+            match event {
                 Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
                     if p.topic == topic_in {
-                        if let Err(e) = input_tx.send(p.payload.to_vec()) {
-                            eprintln!("❌ Failed to forward input: {:?}", e);
+                        let sent = wire.decode_data(&p.payload).map(|input| input_tx.send(input));
+                        match sent {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => eprintln!("❌ Failed to forward input: {:?}", e),
+                            Err(e) => eprintln!("❌ Invalid input: {:?}", e),
+                        }
+                    } else if p.topic == topic_in_z {
+                        let Some(opener) = &mut input_opener else {
+                            continue;
+                        };
+                        let sealed = match wire.decode_data(&p.payload) {
+                            Ok(sealed) => sealed,
+                            Err(e) => {
+                                eprintln!("❌ Invalid input: {:?}", e);
+                                continue;
+                            }
+                        };
+                        match opener.open(&sealed) {
+                            Ok(Some(input)) => {
+                                if let Err(e) = input_tx.send(input) {
+                                    eprintln!("❌ Failed to forward input: {:?}", e);
+                                }
+                            }
+                            // Compressed for an earlier offer
+                            Ok(None) => {}
+                            Err(e) => {
+                                eprintln!("❌ Compressed input lost or corrupt, restarting compression: {}", e);
+                                let algorithm = opener.algorithm();
+                                input_opener = negotiate(&[algorithm], compression, &mut epoch, &codec_tx);
+                            }
                         }
                     } else if p.topic == topic_resize {
-                        if
-                            let Ok(resize_data) = serde_json::from_slice::<TerminalResize>(
-                                &p.payload
-                            )
-                        {
+                        if let Ok(resize_data) = wire.decode_resize(&p.payload).map(|size| size.at_least_one_cell()) {
                             println!(
-                                "📏 Resize request: {}x{}",
+                                "📏 Resize request: {}x{} ({}x{} px)",
                                 resize_data.cols,
-                                resize_data.rows
+                                resize_data.rows,
+                                resize_data.pixel_width,
+                                resize_data.pixel_height
                             );
                             pty_master.master
                                 .resize(PtySize {
                                     rows: resize_data.rows,
                                     cols: resize_data.cols,
-                                    pixel_width: 0,
-                                    pixel_height: 0,
+                                    pixel_width: resize_data.pixel_width,
+                                    pixel_height: resize_data.pixel_height,
                                 })
                                 .expect("Failed to resize pty");
+                            if let Some(screen) = &screen {
+                                screen.resize(resize_data.rows, resize_data.cols);
+                            }
+                        }
+                    } else if p.topic == topic_hello {
+                        session::answer_hello(&client, &wire, &topic_welcome, &offer, true, &p.payload);
+                    } else if p.topic == topic_ctl {
+                        let command = match wire.decode_text(Kind::Control, &p.payload) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                eprintln!("❌ Invalid control command: {:?}", e);
+                                continue;
+                            }
+                        };
+                        match command.as_str() {
+                            "hangup" => {
+                                println!("📴 Hangup requested by controller");
+                                if let Err(e) = killer.kill() {
+                                    eprintln!("❌ Failed to kill shell: {:?}", e);
+                                }
+                            }
+                            "refresh" => {
+                                println!("🔃 Screen refresh requested by controller");
+                                redraw(screen.as_deref(), pty_master.master.as_mut()).await;
+                                // Tells a reconnected controller the agent is reachable
+                                let _ = status_tx.send("shell_ready".to_string());
+                                if let Some(echo) = pty_master.master.as_raw_fd().and_then(echo_status) {
+                                    let _ = status_tx.send(echo.to_string());
+                                }
+                            }
+                            command => {
+                                if let Some(offered) = compress::parse_offer(command) {
+                                    if let Some(opener) = &input_opener {
+                                        println!("🗜️  Input compression ratio was {}", opener.ratio);
+                                    }
+                                    println!("🗜️  Compression offered: {:?}", offered);
+                                    input_opener = negotiate(&offered, compression, &mut epoch, &codec_tx);
+                                } else {
+                                    println!("❓ Unknown control command: '{}'", command);
+                                }
+                            }
                         }
                     }
                 }
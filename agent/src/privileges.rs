@@ -0,0 +1,95 @@
+use nix::unistd::{ self, Uid, User };
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::path::{ Path, PathBuf };
+
+use crate::limits::Limit;
+
+/// Look up the account a session should run as.
+pub fn lookup(name: &str) -> anyhow::Result<User> {
+    match User::from_name(name)? {
+        Some(user) => Ok(user),
+        None => anyhow::bail!("no such user '{}'", name),
+    }
+}
+
+/// What has to happen to a session's process before its command runs.
+/// Nothing can run between fork and exec of a PTY child, so the agent
+/// starts its own binary with the hidden `exec` subcommand to do it.
+#[derive(Default)]
+pub struct Launch {
+    pub user: Option<User>,
+    pub rlimits: Vec<(Limit, u64)>,
+    pub cgroup: Option<PathBuf>,
+}
+
+impl Launch {
+    /// `argv` prefixed with the `exec` subcommand, if there is anything to
+    /// set up.
+    pub fn wrap(&self, argv: Vec<String>) -> anyhow::Result<Vec<String>> {
+        if self.user.is_none() && self.rlimits.is_empty() && self.cgroup.is_none() {
+            return Ok(argv);
+        }
+        let agent = std::env::current_exe()?.to_string_lossy().into_owned();
+        let mut wrapped = vec![agent, "exec".to_string()];
+        if let Some(user) = &self.user {
+            wrapped.extend(["--user".to_string(), user.name.clone()]);
+        }
+        for (limit, value) in &self.rlimits {
+            wrapped.extend(["--rlimit".to_string(), format!("{}={}", limit.name(), value)]);
+        }
+        if let Some(cgroup) = &self.cgroup {
+            wrapped.extend(["--cgroup".to_string(), cgroup.to_string_lossy().into_owned()]);
+        }
+        wrapped.push("--".to_string());
+        wrapped.extend(argv);
+        Ok(wrapped)
+    }
+}
+
+/// Body of the hidden `exec` subcommand, running in the session's PTY with
+/// the agent's privileges: moves into the session cgroup, applies the
+/// rlimits, hands the terminal to `user` and switches to its supplementary
+/// groups, gid and uid, then execs `command`. The environment, HOME
+/// included, has been set by the agent already.
+pub fn exec(
+    user: Option<&str>,
+    rlimits: &[(Limit, u64)],
+    cgroup: Option<&Path>,
+    command: &[String]
+) -> anyhow::Result<Infallible> {
+    if let Some(cgroup) = cgroup {
+        std::fs::write(cgroup.join("cgroup.procs"), std::process::id().to_string())?;
+    }
+    for (limit, value) in rlimits {
+        limit.apply(*value)?;
+    }
+    if let Some(name) = user {
+        switch_user(name)?;
+    }
+    let argv = command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(program) = argv.first() else {
+        anyhow::bail!("no command to run");
+    };
+    Ok(unistd::execvp(program, &argv)?)
+}
+
+fn switch_user(name: &str) -> anyhow::Result<()> {
+    let user = lookup(name)?;
+    if unistd::geteuid() == user.uid {
+        return Ok(());
+    }
+    if let Ok(tty) = unistd::ttyname(0) {
+        unistd::chown(&tty, Some(user.uid), None)?;
+    }
+    unistd::initgroups(&CString::new(name)?, user.gid)?;
+    unistd::setgid(user.gid)?;
+    unistd::setuid(user.uid)?;
+    if !user.uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+        anyhow::bail!("root privileges could not be dropped");
+    }
+    Ok(())
+}
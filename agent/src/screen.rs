@@ -0,0 +1,148 @@
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tokio::sync::{ broadcast, Notify };
+
+/// A full frame is sent at least this often while the screen changes, so a
+/// controller that lost a diff (output is published at QoS 0) recovers.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(30);
+/// Smallest screen kept, in rows and columns: vt100 overflows when a line
+/// wraps on a single row or a wide character is drawn in a single column.
+const MIN_SIZE: u16 = 2;
+
+/// Output that is not part of the screen contents but still meant for the
+/// controller's terminal.
+#[derive(Default)]
+struct Passthrough {
+    bytes: Vec<u8>,
+}
+
+impl vt100::Callbacks for Passthrough {
+    fn audible_bell(&mut self, _: &mut vt100::Screen) {
+        self.bytes.push(b'\x07');
+    }
+
+    fn set_window_title(&mut self, _: &mut vt100::Screen, title: &[u8]) {
+        self.bytes.extend(b"\x1b]2;");
+        self.bytes.extend(title);
+        self.bytes.push(b'\x07');
+    }
+}
+
+struct State {
+    parser: vt100::Parser<Passthrough>,
+    /// The screen as of the last frame.
+    sent: Option<vt100::Screen>,
+    keyframe: Instant,
+}
+
+/// Keeps the terminal screen of the shell and turns it into frames: escape
+/// sequences that bring the controller's terminal from the previous frame
+/// to the current screen. Output is folded into the screen as it comes, so
+/// what gets published depends on how much of the screen changed and not
+/// on how much was written to it.
+pub struct ScreenSync {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl ScreenSync {
+    pub fn new(rows: u16, cols: u16) -> Arc<Self> {
+        let (rows, cols) = (rows.max(MIN_SIZE), cols.max(MIN_SIZE));
+        Arc::new(Self {
+            state: Mutex::new(State {
+                parser: vt100::Parser::new_with_callbacks(rows, cols, 0, Passthrough::default()),
+                sent: None,
+                keyframe: Instant::now(),
+            }),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Fold shell output into the screen.
+    pub fn feed(&self, bytes: &[u8]) {
+        if let Ok(mut state) = self.state.lock() {
+            state.parser.process(bytes);
+        }
+        self.changed.notify_one();
+    }
+
+    pub fn resize(&self, rows: u16, cols: u16) {
+        if let Ok(mut state) = self.state.lock() {
+            state.parser.screen_mut().set_size(rows.max(MIN_SIZE), cols.max(MIN_SIZE));
+            // The controller's terminal was resized too, and may have
+            // rewrapped or scrolled what the last frame drew
+            state.sent = None;
+        }
+        self.changed.notify_one();
+    }
+
+    /// Send the whole screen with the next frame, for a controller that
+    /// just attached or reconnected.
+    pub fn request_full(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.sent = None;
+        }
+        self.changed.notify_one();
+    }
+
+    /// Publish a frame on `frames` whenever the screen changed, at most one
+    /// every `interval`.
+    pub async fn run(self: Arc<Self>, frames: broadcast::Sender<Vec<u8>>, interval: Duration) {
+        loop {
+            self.changed.notified().await;
+            if let Some(frame) = self.frame() {
+                let _ = frames.send(frame);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn frame(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().ok()?;
+        if state.keyframe.elapsed() > KEYFRAME_INTERVAL {
+            state.sent = None;
+        }
+        let screen = state.parser.screen().clone();
+        let mut frame = std::mem::take(&mut state.parser.callbacks_mut().bytes);
+        match &state.sent {
+            Some(sent) => frame.extend(screen.state_diff(sent)),
+            None => {
+                frame.extend(screen.state_formatted());
+                state.keyframe = Instant::now();
+            }
+        }
+        state.sent = Some(screen);
+        (!frame.is_empty()).then_some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_sizes_are_survived() {
+        for (rows, cols) in [(0, 0), (1, 1), (1, 80), (24, 1)] {
+            let screen = ScreenSync::new(rows, cols);
+            screen.feed("wrapping text, 漢字\r\nnext line".as_bytes());
+            assert!(screen.frame().is_some());
+            screen.resize(rows, cols);
+            screen.resize(0, 0);
+            screen.feed("漢字 again".as_bytes());
+            assert!(screen.frame().is_some());
+        }
+    }
+
+    #[test]
+    fn frames_are_diffs_until_a_full_one_is_asked_for() {
+        let screen = ScreenSync::new(24, 80);
+        screen.feed(b"hello");
+        let full = screen.frame().unwrap();
+        screen.feed(b"!");
+        let diff = screen.frame().unwrap();
+        assert!(diff.len() < full.len());
+        assert_eq!(screen.frame(), None);
+        screen.request_full();
+        assert!(screen.frame().unwrap().windows(5).any(|w| w == b"hello"));
+    }
+}
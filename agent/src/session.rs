@@ -0,0 +1,168 @@
+use rumqttc::{ AsyncClient, MqttOptions, QoS };
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use mqttshell_proto::envelope::{ Body, Wire };
+use mqttshell_proto::handshake::{ self, Capability, Welcome };
+use mqttshell_proto::session::{ self, SessionOpen, TERMINAL_ENV };
+
+use crate::shell::ShellOptions;
+
+/// What the agent's welcome offers controllers.
+#[derive(Clone, Debug)]
+pub struct Offer {
+    pub capabilities: Vec<Capability>,
+    pub profiles: Vec<String>,
+    pub users: Vec<String>,
+}
+
+/// Answer a controller's hello on `topic_welcome`, telling it whether a
+/// shell is `running` already.
+pub fn answer_hello(
+    client: &AsyncClient,
+    wire: &Wire,
+    topic_welcome: &str,
+    offer: &Offer,
+    running: bool,
+    payload: &[u8]
+) {
+    let hello = match wire.decode_hello(payload) {
+        Ok(hello) => hello,
+        Err(e) => {
+            eprintln!("❌ Invalid hello: {:?}", e);
+            return;
+        }
+    };
+    let welcome = Welcome {
+        running,
+        ..handshake::welcome(&hello, &offer.capabilities, &offer.profiles, &offer.users)
+    };
+    match &welcome.error {
+        Some(error) => eprintln!("❌ Refusing controller {}: {}", hello.id, error),
+        None => {
+            println!(
+                "🤝 Controller {} attached (protocol {}, capabilities: {})",
+                hello.id,
+                hello.version,
+                hello.capabilities.join(", ")
+            );
+        }
+    }
+    // Not awaited, the event loop has to keep polling for the publish to
+    // make progress
+    let client = client.clone();
+    let topic_welcome = topic_welcome.to_string();
+    let payload = wire.encode(Body::Welcome(welcome));
+    tokio::spawn(async move {
+        let _ = client.publish(&topic_welcome, QoS::AtMostOnce, false, payload).await;
+    });
+}
+
+/// Keep the variables of `env` the agent accepts: [`TERMINAL_ENV`] and
+/// those matching `accepted`.
+fn accept_env(env: BTreeMap<String, String>, accepted: &[String]) -> BTreeMap<String, String> {
+    env.into_iter()
+        .filter(|(name, value)| {
+            let valid = !name.is_empty() && !name.contains(['=', '\0']) && !value.contains('\0');
+            let allowed = valid && (session::matches(&TERMINAL_ENV, name) || session::matches(accepted, name));
+            if !allowed {
+                println!("🚫 Ignoring environment variable {:?} from controller", name);
+            }
+            allowed
+        })
+        .collect()
+}
+
+/// Stay on the broker without a shell until a controller opens a session,
+/// answering hellos meanwhile. A resize from a
+/// controller that predates session requests opens one as well, with the
+/// agent's own environment. Requests for unknown profiles or users are
+/// refused.
+pub async fn wait_for_open(
+    channel: &str,
+    broker: (&str, u16),
+    protocol: (&Arc<Wire>, &Offer),
+    shell: &ShellOptions
+) -> SessionOpen {
+    let (mqtt_host, mqtt_port) = broker;
+    let (wire, offer) = protocol;
+    let topic_open = format!("{}/open", channel);
+    let topic_resize = format!("{}/resize", channel);
+    let topic_hello = format!("{}/hello", channel);
+    let topic_welcome = format!("{}/welcome", channel);
+    let topic_status = format!("{}/status", channel);
+    let mut reconnect_delay = 1;
+
+    println!("⏳ Waiting for a controller to open a session...");
+    loop {
+        let mut mqttoptions = MqttOptions::new("agent", mqtt_host, mqtt_port);
+        mqttoptions.set_keep_alive(Duration::from_secs(5));
+        let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+        // Queued until the event loop below has connected
+        for topic in [&topic_open, &topic_resize, &topic_hello] {
+            if let Err(e) = client.subscribe(topic, QoS::AtMostOnce).await {
+                eprintln!("❌ Failed to subscribe to {}: {:?}", topic, e);
+            }
+        }
+        // Controllers that are already waiting say hello again
+        let online = wire.encode(Body::Status("agent_online".to_string()));
+        let _ = client.publish(&topic_status, QoS::AtMostOnce, false, online).await;
+
+        loop {
+            match eventloop.poll().await {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
+                    if p.topic == topic_open {
+                        match wire.decode_open(&p.payload) {
+                            Ok(open) => {
+                                if let Some(profile) = open.profile.as_deref().filter(|name| !shell.has_profile(name)) {
+                                    eprintln!("❌ Refusing session with unknown profile '{}'", profile);
+                                    continue;
+                                }
+                                if let Some(user) = open.user.as_deref().filter(|name| !shell.allows_user(name)) {
+                                    eprintln!("❌ Refusing session as user '{}', who is not allowed", user);
+                                    continue;
+                                }
+                                println!(
+                                    "📂 Session opened at {}x{} ({} environment variables, profile {}, user {})",
+                                    open.size.cols,
+                                    open.size.rows,
+                                    open.env.len(),
+                                    open.profile.as_deref().unwrap_or("none"),
+                                    open.user.as_deref().unwrap_or("default")
+                                );
+                                let env = accept_env(open.env, &shell.accept_env);
+                                return SessionOpen { env, size: open.size.at_least_one_cell(), ..open };
+                            }
+                            Err(e) => eprintln!("❌ Invalid session request: {:?}", e),
+                        }
+                    } else if p.topic == topic_resize {
+                        match wire.decode_resize(&p.payload) {
+                            Ok(size) => {
+                                println!("📂 Session opened by resize at {}x{}", size.cols, size.rows);
+                                let size = size.at_least_one_cell();
+                                return SessionOpen { size, env: BTreeMap::new(), profile: None, user: None };
+                            }
+                            Err(e) => eprintln!("❌ Invalid resize: {:?}", e),
+                        }
+                    } else if p.topic == topic_hello {
+                        answer_hello(&client, wire, &topic_welcome, offer, false, &p.payload);
+                    }
+                }
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                    println!("🟢 Connected to MQTT broker");
+                    reconnect_delay = 1;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("❌ MQTT error: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        println!("🔄 Reconnecting in {} seconds...", reconnect_delay);
+        tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
+        reconnect_delay = std::cmp::min(reconnect_delay * 2, 30);
+    }
+}
@@ -0,0 +1,211 @@
+use nix::unistd::User;
+use portable_pty::CommandBuilder;
+use std::path::{ Path, PathBuf };
+use mqttshell_proto::session::SessionOpen;
+
+use crate::privileges::{ self, Launch };
+
+/// Program run when neither `--shell` nor a profile says otherwise.
+const DEFAULT_SHELL: &str = "/bin/bash";
+/// Fallback for systems without bash, such as BusyBox based ones.
+const FALLBACK_SHELL: &str = "/bin/sh";
+
+/// A command controllers may ask for by name instead of the shell.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    /// User the profile always runs as.
+    user: Option<String>,
+    argv: Vec<String>,
+}
+
+fn parse_profile(spec: &str) -> anyhow::Result<Profile> {
+    let Some((name, command)) = spec.split_once('=') else {
+        anyhow::bail!("expected NAME[@USER]=COMMAND, got '{}'", spec);
+    };
+    let (name, user) = match name.split_once('@') {
+        Some((name, user)) => (name, Some(user.to_string())),
+        None => (name, None),
+    };
+    let argv: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+    if name.is_empty() || user.as_deref() == Some("") || argv.is_empty() {
+        anyhow::bail!("expected NAME[@USER]=COMMAND, got '{}'", spec);
+    }
+    Ok(Profile { name: name.to_string(), user, argv })
+}
+
+fn parse_env(spec: &str) -> anyhow::Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => anyhow::bail!("expected NAME=VALUE, got '{}'", spec),
+    }
+}
+
+/// How the agent starts shells.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ShellOptions {
+    /// Program to run, looked up in PATH unless it contains a '/'. Defaults
+    /// to /bin/bash, or /bin/sh where there is no bash
+    #[arg(long, value_name = "PROGRAM")]
+    shell: Option<String>,
+
+    /// Argument for the shell program. Can be repeated; the default shell
+    /// gets -i when none is given
+    #[arg(long = "shell-arg", value_name = "ARG", allow_hyphen_values = true)]
+    shell_args: Vec<String>,
+
+    /// Start the shell as a login shell, by passing it -l. Not applied to
+    /// profiles
+    #[arg(long)]
+    login: bool,
+
+    /// Directory the shell starts in, instead of the home directory
+    #[arg(long, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+
+    /// Start the shell with an empty environment instead of the agent's
+    #[arg(long)]
+    clear_env: bool,
+
+    /// Set an environment variable for the shell. Can be repeated
+    #[arg(long = "env", value_name = "NAME=VALUE", value_parser = parse_env)]
+    env: Vec<(String, String)>,
+
+    /// Remove a variable from the shell's environment. Can be repeated
+    #[arg(long = "unset-env", value_name = "NAME")]
+    unset_env: Vec<String>,
+
+    /// Environment variable controllers may set for the shell, besides
+    /// TERM, COLORTERM, LANG and LC_*; a trailing '*' matches any suffix.
+    /// Can be repeated.
+    #[arg(long = "accept-env", value_name = "NAME")]
+    pub accept_env: Vec<String>,
+
+    /// Command a controller may run instead of the shell with
+    /// `--profile NAME`, e.g. `python=python3 -q`, or `backup@backup=...`
+    /// to always run it as the user backup. The command is split at
+    /// whitespace. Can be repeated
+    #[arg(long = "profile", value_name = "NAME[@USER]=COMMAND", value_parser = parse_profile)]
+    profiles: Vec<Profile>,
+
+    /// User sessions run as, instead of the agent's own; needs the agent
+    /// to run as root
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+
+    /// User a controller may ask to run the session as with `--user`. Can
+    /// be repeated
+    #[arg(long = "allow-user", value_name = "NAME")]
+    allow_users: Vec<String>,
+}
+
+impl ShellOptions {
+    /// Names of the profiles controllers may choose from.
+    pub fn profile_names(&self) -> Vec<String> {
+        self.profiles.iter().map(|profile| profile.name.clone()).collect()
+    }
+
+    pub fn has_profile(&self, name: &str) -> bool {
+        self.profile(Some(name)).is_some()
+    }
+
+    fn profile(&self, name: Option<&str>) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| Some(profile.name.as_str()) == name)
+    }
+
+    /// Users controllers may ask for.
+    pub fn user_names(&self) -> Vec<String> {
+        let mut users = self.allow_users.clone();
+        users.extend(self.user.iter().filter(|user| !self.allow_users.contains(user)).cloned());
+        users
+    }
+
+    pub fn allows_user(&self, name: &str) -> bool {
+        self.allow_users.iter().any(|user| user == name) || self.user.as_deref() == Some(name)
+    }
+
+    /// Whether any session may run as another user than the agent's.
+    pub fn switches_user(&self) -> bool {
+        self.user.is_some() || !self.allow_users.is_empty() || self.profiles.iter().any(|profile| profile.user.is_some())
+    }
+
+    /// The account the session `open` asked for runs as: the profile's
+    /// user, else the user the controller asked for, else `--user`. `None`
+    /// keeps the agent's own.
+    pub fn account(&self, open: &SessionOpen) -> anyhow::Result<Option<User>> {
+        let pinned = self.profile(open.profile.as_deref()).and_then(|profile| profile.user.as_deref());
+        let requested = open.user.as_deref().filter(|name| self.allows_user(name));
+        match pinned.or(requested).or(self.user.as_deref()) {
+            Some(name) => privileges::lookup(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Program and arguments for `profile`, or for the shell.
+    fn argv(&self, profile: Option<&str>) -> Vec<String> {
+        if let Some(profile) = self.profile(profile) {
+            return profile.argv.clone();
+        }
+        let mut argv = match &self.shell {
+            Some(shell) => vec![shell.clone()],
+            None if Path::new(DEFAULT_SHELL).exists() => vec![DEFAULT_SHELL.to_string()],
+            None => vec![FALLBACK_SHELL.to_string()],
+        };
+        if self.login {
+            argv.push("-l".to_string());
+        }
+        if self.shell_args.is_empty() && self.shell.is_none() {
+            argv.push("-i".to_string());
+        }
+        argv.extend(self.shell_args.iter().cloned());
+        argv
+    }
+
+    /// The command starting the session `open` asked for, set up by
+    /// `launch`. The environment the controller sent has already been
+    /// filtered; the agent's own settings override it.
+    pub fn command(&self, open: &SessionOpen, launch: &Launch) -> anyhow::Result<CommandBuilder> {
+        let account = launch.user.as_ref();
+        let argv = self.argv(open.profile.as_deref());
+        match account {
+            Some(account) => println!("🐚 Running {} as {}", argv.join(" "), account.name),
+            None => println!("🐚 Running {}", argv.join(" ")),
+        }
+        let argv = launch.wrap(argv)?;
+        let mut cmd = CommandBuilder::from_argv(argv.into_iter().map(Into::into).collect());
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        if let Some(account) = account {
+            // Also where the session starts, unless --cwd says otherwise
+            cmd.env("HOME", &account.dir);
+            cmd.env("USER", &account.name);
+            cmd.env("LOGNAME", &account.name);
+            cmd.env("SHELL", &account.shell);
+        }
+        // Defaults for controllers that do not send their own
+        cmd.env("TERM", "xterm-256color");
+        cmd.env("COLORTERM", "truecolor");
+        for (name, value) in &open.env {
+            cmd.env(name, value);
+        }
+        for (name, value) in &self.env {
+            cmd.env(name, value);
+        }
+        for name in &self.unset_env {
+            cmd.env_remove(name);
+        }
+        if let Some(cwd) = &self.cwd {
+            if !cwd.is_dir() {
+                eprintln!("⚠️  {} is not a directory, starting in the home directory", cwd.display());
+            }
+            cmd.cwd(cwd);
+        }
+        // Accounts like nobody have no home directory to fall back to
+        let usable_cwd = self.cwd.as_ref().is_some_and(|cwd| cwd.is_dir());
+        if !usable_cwd && account.is_some_and(|account| !account.dir.is_dir()) {
+            cmd.cwd("/");
+        }
+        Ok(cmd)
+    }
+}
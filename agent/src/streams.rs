@@ -0,0 +1,412 @@
+use mqttshell_proto::envelope::{ Body, Wire };
+use mqttshell_proto::messages::{ StreamEvent, StreamOpen, StreamTarget };
+use rumqttc::{ AsyncClient, MqttOptions, QoS };
+use std::collections::{ hash_map::Entry, HashMap };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::net::{ TcpStream, UnixStream };
+use tokio::sync::{ mpsc, watch };
+
+/// Byte streams multiplexed over MQTT on behalf of controllers.
+///
+/// Each stream has its own data topics, `<channel>/stream/<id>/up` for
+/// controller to agent traffic and `<channel>/stream/<id>/down` for the
+/// opposite direction. An empty payload on a data topic marks end of stream.
+///
+/// Streams have a broker connection of their own, which outlives shell
+/// restarts and sessions. Data published by either side while it is down
+/// is lost, so a stream fails when the connection it was opened on drops.
+pub struct Streams {
+    topic_open: String,
+    topic_event: String,
+    topic_prefix: String,
+    /// Canonical socket paths, flagged `true` when the entry allows a whole directory.
+    allowed_unix_sockets: Vec<(PathBuf, bool)>,
+    /// Directory uploads are stored in, `None` when uploads are refused.
+    upload_dir: Option<PathBuf>,
+    wire: Arc<Wire>,
+    /// Client of the current broker connection, `None` while it is down.
+    connection: watch::Sender<Option<AsyncClient>>,
+    writers: Mutex<HashMap<String, mpsc::Sender<Vec<u8>>>>,
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Chunks received for a stream that its target has not taken yet. A
+/// stream whose target falls further behind is closed.
+const STREAM_BUFFER: usize = 256;
+const MAX_ID_LENGTH: usize = 64;
+
+/// Whether `id` can be part of a topic name without reaching into other
+/// streams' topics or acting as a wildcard.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LENGTH && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+impl Streams {
+    /// `allowed_unix_sockets` lists the socket paths controllers may open;
+    /// an entry ending in `/` allows every socket below that directory.
+    pub fn new(
+        channel: &str,
+        allowed_unix_sockets: Vec<PathBuf>,
+        upload_dir: Option<PathBuf>,
+        wire: Arc<Wire>
+    ) -> Arc<Self> {
+        let allowed_unix_sockets = allowed_unix_sockets
+            .into_iter()
+            .map(|entry| {
+                let is_dir = entry.to_string_lossy().ends_with('/');
+                (entry.canonicalize().unwrap_or(entry), is_dir)
+            })
+            .collect();
+        Arc::new(Self {
+            allowed_unix_sockets,
+            upload_dir,
+            wire,
+            topic_open: format!("{}/stream/open", channel),
+            topic_event: format!("{}/stream/event", channel),
+            topic_prefix: format!("{}/stream/", channel),
+            connection: watch::Sender::new(None),
+            writers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Keep the stream connection to the broker up, reconnecting with a
+    /// backoff. Runs for the lifetime of the agent.
+    pub async fn serve(self: Arc<Self>, client_id: String, broker: (String, u16)) {
+        let (mqtt_host, mqtt_port) = broker;
+        let mut reconnect_delay = 1;
+        loop {
+            let mut mqttoptions = MqttOptions::new(client_id.clone(), mqtt_host.clone(), mqtt_port);
+            mqttoptions.set_keep_alive(Duration::from_secs(5));
+            let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+            // Queued until the event loop below has connected
+            for topic in [self.topic_open.clone(), format!("{}+/up", self.topic_prefix)] {
+                if let Err(e) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                    eprintln!("❌ Failed to subscribe to {}: {:?}", topic, e);
+                }
+            }
+
+            loop {
+                match eventloop.poll().await {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(p))) => {
+                        self.dispatch(&p.topic, &p.payload);
+                    }
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        println!("🟢 Stream connection to the broker is up");
+                        reconnect_delay = 1;
+                        self.connection.send_replace(Some(client.clone()));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("❌ MQTT error on the stream connection: {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            // Fails the streams opened over it
+            self.connection.send_replace(None);
+            println!("🔄 Reconnecting the stream connection in {} seconds...", reconnect_delay);
+            tokio::time::sleep(Duration::from_secs(reconnect_delay)).await;
+            reconnect_delay = std::cmp::min(reconnect_delay * 2, 30);
+        }
+    }
+
+    /// Handle a publish on one of the stream topics.
+    fn dispatch(self: &Arc<Self>, topic: &str, payload: &[u8]) {
+        if topic == self.topic_open {
+            match self.wire.decode_stream_open(payload) {
+                Ok(request) => {
+                    let streams = Arc::clone(self);
+                    tokio::spawn(async move { streams.open(request).await });
+                }
+                Err(e) => eprintln!("❌ Invalid stream open request: {:?}", e),
+            }
+            return;
+        }
+
+        let id = match
+            topic.strip_prefix(&self.topic_prefix).and_then(|rest| rest.strip_suffix("/up"))
+        {
+            Some(id) => id,
+            None => {
+                return;
+            }
+        };
+        let writer = self.writers.lock().ok().and_then(|w| w.get(id).cloned());
+        match (writer, self.wire.decode_data(payload)) {
+            (Some(writer), Ok(data)) => {
+                if let Err(mpsc::error::TrySendError::Full(_)) = writer.try_send(data) {
+                    eprintln!("❌ Stream {} is not keeping up, closing it", id);
+                    if let Ok(mut writers) = self.writers.lock() {
+                        writers.remove(id);
+                    }
+                }
+            }
+            (Some(_), Err(e)) => eprintln!("❌ Invalid data for stream '{}': {:?}", id, e),
+            (None, _) => eprintln!("⚠️  Data for unknown stream '{}'", id),
+        }
+    }
+
+    async fn open(self: Arc<Self>, request: StreamOpen) {
+        let StreamOpen { id, target } = request;
+        if !valid_id(&id) {
+            let error = format!("invalid stream id {:?}", id);
+            eprintln!("❌ Refusing stream: {}", error);
+            self.publish_event(StreamEvent::Failed { id, error }).await;
+            return;
+        }
+        // Requests are published at QoS 1 and may arrive twice, the id is
+        // taken before dialling so that a repeat does not dial again
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(STREAM_BUFFER);
+        let reserved = self.writers
+            .lock()
+            .map(|mut writers| {
+                match writers.entry(id.clone()) {
+                    Entry::Occupied(_) => false,
+                    Entry::Vacant(entry) => {
+                        entry.insert(tx);
+                        true
+                    }
+                }
+            })
+            .unwrap_or(false);
+        if !reserved {
+            eprintln!("⚠️  Stream {} is already open, ignoring the repeated request", id);
+            return;
+        }
+        println!("🔀 Opening stream {} to {:?}", id, target);
+
+        let result = match target {
+            StreamTarget::File { name } => {
+                match self.create_upload(&name).await {
+                    Ok((path, file)) => {
+                        return self.receive(id, rx, path, file).await;
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            StreamTarget::Tcp { host, port } => {
+                match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port))).await {
+                    Ok(Ok(stream)) => {
+                        let _ = stream.set_nodelay(true);
+                        let (reader, writer) = stream.into_split();
+                        Ok((Box::new(reader) as BoxedReader, Box::new(writer) as BoxedWriter))
+                    }
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err("connection timed out".to_string()),
+                }
+            }
+            StreamTarget::Unix { path } => {
+                match self.check_unix_socket(Path::new(&path)) {
+                    Ok(path) =>
+                        match UnixStream::connect(&path).await {
+                            Ok(stream) => {
+                                let (reader, writer) = stream.into_split();
+                                Ok((Box::new(reader) as BoxedReader, Box::new(writer) as BoxedWriter))
+                            }
+                            Err(e) => Err(e.to_string()),
+                        }
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        match result {
+            Ok((reader, writer)) => self.run(id, rx, reader, writer).await,
+            Err(error) => {
+                eprintln!("❌ Stream {} failed: {}", id, error);
+                if let Ok(mut writers) = self.writers.lock() {
+                    writers.remove(&id);
+                }
+                self.publish_event(StreamEvent::Failed { id, error }).await;
+            }
+        }
+    }
+
+    async fn run(
+        self: Arc<Self>,
+        id: String,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        mut reader: BoxedReader,
+        mut writer: BoxedWriter
+    ) {
+        tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                if data.is_empty() {
+                    let _ = writer.shutdown().await;
+                    break;
+                }
+                if writer.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut connection = self.connection.subscribe();
+        let client = connection.borrow_and_update().clone();
+        let topic_down = format!("{}{}/down", self.topic_prefix, id);
+        let ended = match client {
+            Some(client) => self.forward(&client, &mut connection, &id, &topic_down, &mut reader).await,
+            None => Err("the agent is not connected to the broker".to_string()),
+        };
+
+        // A failure is not passed on as end of stream, which the controller
+        // would take for a complete transfer
+        if let Err(error) = ended {
+            eprintln!("❌ Stream {} failed: {}", id, error);
+            self.publish_event(StreamEvent::Failed { id: id.clone(), error }).await;
+        }
+        if let Ok(mut writers) = self.writers.lock() {
+            writers.remove(&id);
+        }
+        println!("🔀 Stream {} closed", id);
+    }
+
+    /// Announce the stream and publish what `reader` yields until it ends,
+    /// all over the broker connection of `client`. Fails if a publish does
+    /// or the connection changes, as data may have been lost then.
+    async fn forward(
+        &self,
+        client: &AsyncClient,
+        connection: &mut watch::Receiver<Option<AsyncClient>>,
+        id: &str,
+        topic_down: &str,
+        reader: &mut BoxedReader
+    ) -> Result<(), String> {
+        let lost = |_| "the agent's broker connection was lost".to_string();
+        let opened = self.wire.encode(Body::StreamEvent(StreamEvent::Opened { id: id.to_string() }));
+        client.publish(&self.topic_event, QoS::AtLeastOnce, false, opened).await.map_err(lost)?;
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let n = tokio::select! {
+                read = reader.read(&mut buf) => read.map_err(|e| e.to_string())?,
+                _ = connection.changed() => {
+                    return Err("the agent's broker connection dropped".to_string());
+                }
+            };
+            let payload = self.wire.encode(Body::Data(buf[..n].to_vec()));
+            client.publish(topic_down, QoS::AtLeastOnce, false, payload).await.map_err(lost)?;
+            if n == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn receive(
+        self: Arc<Self>,
+        id: String,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        path: PathBuf,
+        mut file: tokio::fs::File
+    ) {
+        let mut connection = self.connection.subscribe();
+        let client = connection.borrow_and_update().clone();
+        let topic_down = format!("{}{}/down", self.topic_prefix, id);
+        let stored = match client {
+            Some(client) => self.store(&client, &mut connection, &id, &topic_down, &mut rx, &mut file).await,
+            None => Err("the agent is not connected to the broker".to_string()),
+        };
+
+        match stored {
+            Ok(size) => println!("📥 Stream {} stored {} ({} bytes)", id, path.display(), size),
+            Err(error) => {
+                eprintln!("❌ Stream {} failed: {}", id, error);
+                // A partial file could pass for the complete upload
+                let _ = tokio::fs::remove_file(&path).await;
+                self.publish_event(StreamEvent::Failed { id: id.clone(), error }).await;
+            }
+        }
+        if let Ok(mut writers) = self.writers.lock() {
+            writers.remove(&id);
+        }
+        println!("🔀 Stream {} closed", id);
+    }
+
+    /// Announce the stream and write what arrives on `rx` into `file` until
+    /// end of stream, which is answered once the file is on disk. Returns
+    /// the number of bytes stored.
+    async fn store(
+        &self,
+        client: &AsyncClient,
+        connection: &mut watch::Receiver<Option<AsyncClient>>,
+        id: &str,
+        topic_down: &str,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+        file: &mut tokio::fs::File
+    ) -> Result<u64, String> {
+        let lost = |_| "the agent's broker connection was lost".to_string();
+        let opened = self.wire.encode(Body::StreamEvent(StreamEvent::Opened { id: id.to_string() }));
+        client.publish(&self.topic_event, QoS::AtLeastOnce, false, opened).await.map_err(lost)?;
+        let mut size = 0u64;
+        loop {
+            let data = tokio::select! {
+                data = rx.recv() => data.ok_or_else(|| "the upload fell behind and was closed".to_string())?,
+                _ = connection.changed() => {
+                    return Err("the agent's broker connection dropped".to_string());
+                }
+            };
+            if data.is_empty() {
+                file.sync_all().await.map_err(|e| e.to_string())?;
+                let payload = self.wire.encode(Body::Data(Vec::new()));
+                client.publish(topic_down, QoS::AtLeastOnce, false, payload).await.map_err(lost)?;
+                return Ok(size);
+            }
+            file.write_all(&data).await.map_err(|e| e.to_string())?;
+            size += data.len() as u64;
+        }
+    }
+
+    /// Create the file an upload named `name` is stored in. Only plain file
+    /// names are taken, and existing files are never replaced.
+    async fn create_upload(&self, name: &str) -> Result<(PathBuf, tokio::fs::File), String> {
+        let dir = self.upload_dir.as_ref().ok_or_else(|| "uploads are not allowed on this agent".to_string())?;
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+            return Err(format!("invalid file name {:?}", name));
+        }
+        let path = dir.join(name);
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path).await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok((path, file))
+    }
+
+    /// Resolve `path` and make sure it is covered by the allowlist.
+    fn check_unix_socket(&self, path: &Path) -> Result<PathBuf, String> {
+        let resolved = path.canonicalize().map_err(|e| format!("{}: {}", path.display(), e))?;
+        let allowed = self.allowed_unix_sockets.iter().any(|(allowed, is_dir)| {
+            if *is_dir { resolved.starts_with(allowed) } else { resolved == *allowed }
+        });
+        if allowed {
+            Ok(resolved)
+        } else {
+            Err(format!("{} is not an allowed Unix socket", path.display()))
+        }
+    }
+
+    /// Publish `event` on the broker connection, waiting for it to be up
+    /// again if it is down.
+    async fn publish_event(&self, event: StreamEvent) {
+        let payload = self.wire.encode(Body::StreamEvent(event));
+        let mut connection = self.connection.subscribe();
+        loop {
+            let client = connection.borrow_and_update().clone();
+            if let Some(client) = client {
+                if client.publish(&self.topic_event, QoS::AtLeastOnce, false, payload.clone()).await.is_ok() {
+                    return;
+                }
+            }
+            if connection.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
@@ -0,0 +1,89 @@
+use mqttshell_proto::compress::{ self, Algorithm, Opener, Sealer };
+use std::io;
+
+/// Input shorter than this goes out uncompressed: a keystroke only grows
+/// by the framing, and the agent takes plain input at any time.
+const MIN_COMPRESSED_INPUT: usize = 64;
+
+/// Which payload compression the controller offers the agent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompressMode {
+    /// zstd, or deflate if the agent prefers.
+    #[default]
+    Auto,
+    Zstd,
+    Deflate,
+    /// Ask the agent for uncompressed payloads.
+    Off,
+}
+
+/// Compression state of the session: the decoder for `<channel>/out/z` and
+/// the encoder for `<channel>/in/z`, both set up from the agent's reply to
+/// the offer. Output is decoded even with [`CompressMode::Off`], for when
+/// another controller on the channel negotiated compression.
+pub struct Codec {
+    mode: CompressMode,
+    pub output: Option<Opener>,
+    pub input: Option<Sealer>,
+}
+
+impl Codec {
+    pub fn new(mode: CompressMode) -> Self {
+        Self { mode, output: None, input: None }
+    }
+
+    /// Control command offering compression; an empty offer asks for none.
+    pub fn offer(&self) -> String {
+        compress::offer(match self.mode {
+            CompressMode::Auto => &Algorithm::ALL,
+            CompressMode::Zstd => &[Algorithm::Zstd],
+            CompressMode::Deflate => &[Algorithm::Deflate],
+            CompressMode::Off => &[],
+        })
+    }
+
+    /// Follow the agent's reply to an offer, from this controller or
+    /// another one.
+    pub fn accept(&mut self, chosen: Option<(Algorithm, u8)>) {
+        self.output = chosen.and_then(|(algorithm, epoch)| Opener::new(algorithm, epoch).ok());
+        self.input = chosen
+            .filter(|_| self.mode != CompressMode::Off)
+            .and_then(|(algorithm, epoch)| Sealer::new(algorithm, epoch).ok());
+    }
+
+    /// Decode a payload from `<channel>/out/z`, or `None` while there is
+    /// nothing to decode it with. On errors the decoder is dropped, and the
+    /// agent has to be offered compression again.
+    pub fn open_output(&mut self, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let Some(opener) = &mut self.output else {
+            return Ok(None);
+        };
+        let opened = opener.open(payload);
+        if opened.is_err() {
+            self.output = None;
+        }
+        opened
+    }
+
+    /// Encode input for `<channel>/in/z`, or `None` to send it as is.
+    pub fn seal_input(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        if input.len() < MIN_COMPRESSED_INPUT {
+            return None;
+        }
+        let sealed = self.input.as_mut()?.seal(input);
+        if sealed.is_err() {
+            self.input = None;
+        }
+        sealed.ok()
+    }
+
+    /// Algorithm and ratios achieved so far, for the status display.
+    pub fn summary(&self) -> Option<String> {
+        let output = self.output.as_ref()?;
+        let mut summary = format!("{} out {}", output.algorithm(), output.ratio);
+        if let Some(input) = &self.input {
+            summary.push_str(&format!(" in {}", input.ratio));
+        }
+        Some(summary)
+    }
+}
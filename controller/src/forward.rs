@@ -0,0 +1,127 @@
+use crate::streams::StreamMux;
+use mqttshell_proto::messages::StreamTarget;
+use nix::sys::stat::{ self, Mode };
+use nix::unistd;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use tokio::net::{ TcpListener, UnixListener };
+
+/// Local side of a forward, either a TCP port or a Unix socket path.
+pub enum LocalListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl LocalListener {
+    pub fn describe(&self) -> String {
+        match self {
+            LocalListener::Tcp(listener) =>
+                listener
+                    .local_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default(),
+            LocalListener::Unix(_, path) => path.display().to_string(),
+        }
+    }
+}
+
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        if let LocalListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Parse an ssh-style `[bind_address:]port` specification.
+pub fn parse_bind(spec: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = match spec.rsplit_once(':') {
+        Some((host, port)) => (host.trim_matches(|c| c == '[' || c == ']').to_string(), port),
+        None => ("127.0.0.1".to_string(), spec),
+    };
+    let port = port.parse::<u16>().map_err(|_| anyhow::anyhow!("invalid port in '{}'", spec))?;
+    Ok((host, port))
+}
+
+/// Parse a `--unix-forward` argument, `LOCAL:REMOTE_SOCKET`, where LOCAL is
+/// `[bind_address:]port` or a local socket path.
+pub async fn bind_unix_forward(spec: &str) -> anyhow::Result<(LocalListener, StreamTarget)> {
+    let (local, remote) = spec
+        .split_once(":/")
+        .ok_or_else(|| anyhow::anyhow!("expected LOCAL:/remote/socket, got '{}'", spec))?;
+    let target = StreamTarget::Unix { path: format!("/{}", remote) };
+
+    let listener = if local.contains('/') {
+        let path = PathBuf::from(local);
+        remove_stale_socket(&path)?;
+        LocalListener::Unix(bind_private(&path)?, path)
+    } else {
+        let (host, port) = parse_bind(local)?;
+        LocalListener::Tcp(TcpListener::bind((host.as_str(), port)).await?)
+    };
+    Ok((listener, target))
+}
+
+/// Bind a socket only the current user can connect to, like ssh's
+/// StreamLocalBindMask: whoever reaches it reaches the remote socket, with
+/// the agent's privileges.
+fn bind_private(path: &Path) -> std::io::Result<UnixListener> {
+    let previous = stat::umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    stat::umask(previous);
+    listener
+}
+
+/// Remove a socket file left behind by a controller that did not exit
+/// cleanly. Other files and other users' sockets are refused; a live
+/// socket is kept for the bind to report as in use.
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::{ FileTypeExt, MetadataExt };
+
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    if metadata.uid() != unistd::geteuid().as_raw() {
+        anyhow::bail!("{} is a socket of another user", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Accept local connections and forward each one to `target` through the agent.
+pub async fn serve(
+    mux: Arc<StreamMux>,
+    listener: LocalListener,
+    target: StreamTarget
+) -> anyhow::Result<()> {
+    loop {
+        let mux = Arc::clone(&mux);
+        let target = target.clone();
+        match &listener {
+            LocalListener::Tcp(tcp) => {
+                let (socket, _) = tcp.accept().await?;
+                let _ = socket.set_nodelay(true);
+                tokio::spawn(async move {
+                    if let Ok(stream) = mux.open(target).await {
+                        let (reader, writer) = socket.into_split();
+                        let _ = mux.pump(stream, reader, writer).await;
+                    }
+                });
+            }
+            LocalListener::Unix(unix, _) => {
+                let (socket, _) = unix.accept().await?;
+                tokio::spawn(async move {
+                    if let Ok(stream) = mux.open(target).await {
+                        let (reader, writer) = socket.into_split();
+                        let _ = mux.pump(stream, reader, writer).await;
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crossterm::terminal;
+use std::io::{ self, Write };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::OnceLock;
+use tokio::signal::unix::{ signal, SignalKind };
+
+/// Modes the remote application may have switched on in the local
+/// terminal, turned off again: alternate screen (left without restoring
+/// the cursor, which `?1049l` would do even outside of it), mouse
+/// tracking and encodings, focus reports, bracketed paste, application
+/// cursor and keypad, modifyOtherKeys, scroll region, hidden cursor and
+/// SGR attributes.
+const RESET: &str = concat!(
+    "\x1b[?1047l\x1b[?47l",
+    "\x1b[?9l\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1005l\x1b[?1006l\x1b[?1015l",
+    "\x1b[?1004l\x1b[?2004l",
+    "\x1b[?1l\x1b>\x1b[>4m",
+    "\x1b[r\x1b[?25h\x1b[0m"
+);
+
+/// Drops every kitty keyboard protocol flag the remote pushed.
+const KITTY_RESET: &str = "\x1b[=0;1u\x1b[<99u";
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SEQUENCE: OnceLock<String> = OnceLock::new();
+
+/// Puts the local terminal back the way it was, whichever way the
+/// controller exits: when dropped, from a panic hook, and on SIGTERM,
+/// SIGHUP, SIGINT or SIGQUIT. Install it right after enabling raw mode.
+/// A panic in any thread ends the controller.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    /// `local_kitty` says whether the local terminal speaks the kitty
+    /// keyboard protocol, whose flags then need resetting too; `extra` is
+    /// written last.
+    pub fn install(local_kitty: bool, extra: &str) -> Self {
+        let mut sequence = RESET.to_string();
+        if local_kitty {
+            sequence.push_str(KITTY_RESET);
+        }
+        sequence.push_str(extra);
+        let _ = SEQUENCE.set(sequence);
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(
+            Box::new(move |info| {
+                restore();
+                previous(info);
+                // A panicking task would otherwise leave the session running
+                // on a terminal that is no longer in raw mode
+                std::process::exit(101);
+            })
+        );
+
+        for kind in [
+            SignalKind::terminate(),
+            SignalKind::hangup(),
+            SignalKind::interrupt(),
+            SignalKind::quit(),
+        ] {
+            if let Ok(mut signals) = signal(kind) {
+                tokio::spawn(async move {
+                    if signals.recv().await.is_some() {
+                        restore();
+                        std::process::exit(128 + kind.as_raw_value());
+                    }
+                });
+            }
+        }
+        TerminalGuard
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Reset the terminal modes and leave raw mode. Only the first call does
+/// anything.
+pub fn restore() {
+    if !ACTIVE.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let mut stdout = io::stdout();
+    if let Some(sequence) = SEQUENCE.get() {
+        let _ = stdout.write_all(sequence.as_bytes());
+    }
+    let _ = stdout.flush();
+    let _ = terminal::disable_raw_mode();
+}
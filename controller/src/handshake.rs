@@ -0,0 +1,138 @@
+use mqttshell_proto::handshake::{
+    self,
+    Capability,
+    Hello,
+    Welcome,
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+use mqttshell_proto::session::SessionOpen;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// How long to wait for the agent's welcome before taking it for an agent
+/// that predates the handshake.
+pub const WELCOME_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a welcome means for this controller.
+pub enum Attached {
+    /// The answer to another controller's hello.
+    Other,
+    /// The agent accepted; the capabilities both sides support.
+    Accepted(Vec<Capability>),
+    /// The agent refused, or speaks a protocol this controller does not.
+    Refused(String),
+}
+
+/// The controller's side of the hello/welcome exchange.
+pub struct Handshake {
+    id: String,
+    capabilities: Vec<Capability>,
+    /// Counts hellos, so a late timeout does not apply to a newer one.
+    attempt: u32,
+    /// Whether the latest hello awaits its answer. Only one welcome or
+    /// timeout per hello leads to announcing the session.
+    pending: bool,
+    /// Capabilities shared with the agent, none with an agent that predates
+    /// the handshake.
+    common: Vec<Capability>,
+    /// Whether the agent already runs a shell.
+    running: bool,
+    /// Agent profile the session has to run.
+    profile: Option<String>,
+    /// User the session has to run as.
+    user: Option<String>,
+}
+
+impl Handshake {
+    /// `open` is the session request the controller is going to send.
+    pub fn new(capabilities: Vec<Capability>, open: &SessionOpen) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Self {
+            id: format!("{:x}{:08x}", std::process::id(), nanos),
+            capabilities,
+            attempt: 0,
+            pending: false,
+            common: Vec::new(),
+            running: false,
+            profile: open.profile.clone(),
+            user: open.user.clone(),
+        }
+    }
+
+    /// Start an attempt; returns its number and the hello to publish.
+    pub fn hello(&mut self) -> (u32, Hello) {
+        self.attempt += 1;
+        self.pending = true;
+        let hello = Hello {
+            id: self.id.clone(),
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: handshake::names(&self.capabilities),
+        };
+        (self.attempt, hello)
+    }
+
+    pub fn welcome(&mut self, welcome: Welcome) -> Attached {
+        // Answers to this controller's earlier hellos, or arriving after the
+        // timeout, are ignored like other controllers'
+        if welcome.id != self.id || !self.pending {
+            return Attached::Other;
+        }
+        self.pending = false;
+        self.running = welcome.running;
+        let refusal = welcome.error.clone().or_else(|| {
+            handshake::incompatibility("controller", "agent", welcome.version, welcome.min_version)
+        });
+        if let Some(error) = refusal {
+            return Attached::Refused(error);
+        }
+        self.common = handshake::common(&self.capabilities, &welcome.capabilities);
+        if let Some(error) = self.unavailable(&welcome) {
+            return Attached::Refused(error);
+        }
+        Attached::Accepted(self.common.clone())
+    }
+
+    /// Whether `attempt` went unanswered, called once its welcome timed
+    /// out. The agent is then treated as one without any capability.
+    pub fn unanswered(&mut self, attempt: u32) -> bool {
+        if !self.pending || attempt != self.attempt {
+            return false;
+        }
+        self.pending = false;
+        self.common.clear();
+        self.running = false;
+        true
+    }
+
+    /// Why the agent cannot open the session this controller asks for.
+    fn unavailable(&self, welcome: &Welcome) -> Option<String> {
+        if (self.profile.is_some() || self.user.is_some()) && !self.supports(Capability::Session) {
+            return Some("the agent cannot choose profiles or users, upgrade the agent".to_string());
+        }
+        if let Some(profile) = self.profile.as_ref().filter(|profile| !welcome.profiles.contains(profile)) {
+            return Some(
+                format!("the agent has no profile '{}' (available: {})", profile, welcome.profiles.join(", "))
+            );
+        }
+        if let Some(user) = self.user.as_ref().filter(|user| !welcome.users.contains(user)) {
+            return Some(
+                format!("the agent does not run sessions as '{}' (allowed: {})", user, welcome.users.join(", "))
+            );
+        }
+        None
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.common.contains(&capability)
+    }
+
+    /// Whether the agent has to be asked to open the session: it waits for
+    /// one instead of running a shell.
+    pub fn opens(&self) -> bool {
+        self.supports(Capability::Session) && !self.running
+    }
+}
@@ -0,0 +1,200 @@
+use crate::compress::Codec;
+use crate::output::{ notify, InputEncoder, Output };
+use mqttshell_proto::envelope::{ Body, Wire };
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{ AsyncClient, Request };
+use std::collections::VecDeque;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tokio::sync::{ mpsc, watch };
+
+/// What happens to input typed while the broker connection is down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputPolicy {
+    /// Discard it and warn.
+    Drop,
+    /// Hold it, up to a limit, and send what has not expired once
+    /// reconnected.
+    #[default]
+    Queue,
+    /// Stop reading the local terminal until reconnected.
+    Block,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InputOptions {
+    pub policy: InputPolicy,
+    /// Most bytes held by [`InputPolicy::Queue`].
+    pub queue_limit: usize,
+    /// Age after which input is no longer sent, also set as the MQTT message
+    /// expiry interval. Zero keeps input forever.
+    pub expiry: Duration,
+}
+
+/// Tells the input loops whether to read the local terminal.
+#[derive(Clone)]
+pub struct InputGate {
+    block: bool,
+    connected: watch::Receiver<bool>,
+}
+
+impl InputGate {
+    pub fn new(policy: InputPolicy, connected: watch::Receiver<bool>) -> Self {
+        Self {
+            block: policy == InputPolicy::Block,
+            connected,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.block || *self.connected.borrow()
+    }
+
+    /// Wait for the connection state to change.
+    pub async fn changed(&mut self) {
+        if self.connected.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Input waiting for the connection to come back.
+struct Held {
+    messages: VecDeque<(Instant, Vec<u8>)>,
+    bytes: usize,
+}
+
+/// Publish input from `rx_input` on `topic`, applying the remote charset,
+/// the compression negotiated with the agent, and `options` while
+/// `connected` is false. Input is never handed to the
+/// MQTT client during an outage, where it would pile up and be sent in one
+/// burst on reconnection.
+pub async fn publish(
+    client: AsyncClient,
+    topic: String,
+    mut rx_input: mpsc::UnboundedReceiver<Vec<u8>>,
+    mut connected: watch::Receiver<bool>,
+    options: InputOptions,
+    encoders: (Option<InputEncoder>, Arc<Mutex<Codec>>, Arc<Wire>),
+    output: Arc<Mutex<Output>>
+) {
+    let (mut encoder, codec, wire) = encoders;
+    let properties = PublishProperties {
+        message_expiry_interval: (!options.expiry.is_zero()).then(||
+            options.expiry.as_secs().clamp(1, u32::MAX as u64) as u32
+        ),
+        ..PublishProperties::default()
+    };
+    let mut held = Held { messages: VecDeque::new(), bytes: 0 };
+    let mut warned = false;
+    loop {
+        tokio::select! {
+            input = rx_input.recv() => {
+                let Some(input) = input else {
+                    break;
+                };
+                let input = match &mut encoder {
+                    Some(encoder) => encoder.encode(&input),
+                    None => input,
+                };
+                if *connected.borrow() {
+                    send(&client, &topic, &properties, input, (&codec, &wire, &output)).await;
+                    continue;
+                }
+                let full = held.bytes + input.len() > options.queue_limit;
+                match options.policy {
+                    InputPolicy::Drop => {
+                        warn(&output, &mut warned, "Not connected, input dropped");
+                    }
+                    InputPolicy::Queue if full => {
+                        warn(&output, &mut warned, "Not connected, input queue full");
+                    }
+                    // Blocked input loops still let terminal replies through
+                    InputPolicy::Queue | InputPolicy::Block => {
+                        held.bytes += input.len();
+                        held.messages.push_back((Instant::now(), input));
+                    }
+                }
+            }
+            changed = connected.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                if !*connected.borrow_and_update() {
+                    continue;
+                }
+                warned = false;
+                let mut expired = 0;
+                for (typed, input) in held.messages.drain(..) {
+                    if !options.expiry.is_zero() && typed.elapsed() > options.expiry {
+                        expired += input.len();
+                    } else {
+                        send(&client, &topic, &properties, input, (&codec, &wire, &output)).await;
+                    }
+                }
+                held.bytes = 0;
+                if expired > 0 {
+                    notify(&output, format!("Discarded {} bytes of input typed too long ago", expired));
+                }
+            }
+        }
+    }
+}
+
+/// Remove the input that was still in the MQTT client's queue when the
+/// connection dropped, if the outage lasted longer than `expiry`: it was
+/// typed before the outage, so it is older than that. The client would
+/// send it on reconnection, and the broker cannot expire what it has not
+/// received yet. Returns how many messages were removed.
+pub fn discard_stale(pending: &mut VecDeque<Request>, topic: &str, outage: Duration, expiry: Duration) -> usize {
+    if expiry.is_zero() || outage <= expiry {
+        return 0;
+    }
+    let compressed = format!("{}/z", topic);
+    let before = pending.len();
+    pending.retain(|request| match request {
+        Request::Publish(publish) => publish.topic != topic.as_bytes() && publish.topic != compressed.as_bytes(),
+        _ => true,
+    });
+    before - pending.len()
+}
+
+async fn send(
+    client: &AsyncClient,
+    topic: &str,
+    properties: &PublishProperties,
+    input: Vec<u8>,
+    state: (&Mutex<Codec>, &Wire, &Mutex<Output>)
+) {
+    let (codec, wire, output) = state;
+    // Sealed only now, held input may date from before a renegotiation
+    let sealed = codec.lock().ok().and_then(|mut codec| codec.seal_input(&input));
+    let (topic, input) = match sealed {
+        Some(sealed) => (format!("{}/z", topic), sealed),
+        None => (topic.to_string(), input),
+    };
+    let result = client.publish_with_properties(
+        topic,
+        QoS::AtMostOnce,
+        false,
+        wire.encode(Body::Data(input)),
+        properties.clone()
+    ).await;
+    if let Err(e) = result {
+        notify(output, format!("Error sending input: {:?}", e));
+    }
+}
+
+/// Report lost input once per outage, ringing the local bell as well since
+/// the notice is not shown without a status display.
+fn warn(output: &Mutex<Output>, warned: &mut bool, message: &str) {
+    if std::mem::replace(warned, true) {
+        return;
+    }
+    if let Ok(mut output) = output.lock() {
+        let _ = output.write_local("\x07");
+        let _ = output.notify(message.to_string());
+        let _ = output.flush();
+    }
+}
@@ -0,0 +1,370 @@
+use crate::kitty;
+use crate::modes::RemoteModes;
+use crossterm::event::{ KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers };
+
+const ESC: u8 = 0x1b;
+
+/// How a non-character key is encoded.
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// Cursor-style key, `CSI x` (or `SS3 x` in application cursor mode),
+    /// `CSI 1 ; m x` when modified.
+    Cursor(u8),
+    /// `SS3 x` when unmodified, `CSI 1 ; m x` when modified (F1-F4).
+    Ss3(u8),
+    /// `CSI n ~`, `CSI n ; m ~` when modified.
+    Tilde(u8),
+}
+
+const NAVIGATION_KEYS: &[(KeyCode, Encoding)] = &[
+    (KeyCode::Up, Encoding::Cursor(b'A')),
+    (KeyCode::Down, Encoding::Cursor(b'B')),
+    (KeyCode::Right, Encoding::Cursor(b'C')),
+    (KeyCode::Left, Encoding::Cursor(b'D')),
+    (KeyCode::Home, Encoding::Cursor(b'H')),
+    (KeyCode::End, Encoding::Cursor(b'F')),
+    (KeyCode::Insert, Encoding::Tilde(2)),
+    (KeyCode::Delete, Encoding::Tilde(3)),
+    (KeyCode::PageUp, Encoding::Tilde(5)),
+    (KeyCode::PageDown, Encoding::Tilde(6)),
+];
+
+/// F1-F12. F13-F24 are sent as Shift+F1-F12, as xterm's terminfo describes them.
+const FUNCTION_KEYS: [Encoding; 12] = [
+    Encoding::Ss3(b'P'),
+    Encoding::Ss3(b'Q'),
+    Encoding::Ss3(b'R'),
+    Encoding::Ss3(b'S'),
+    Encoding::Tilde(15),
+    Encoding::Tilde(17),
+    Encoding::Tilde(18),
+    Encoding::Tilde(19),
+    Encoding::Tilde(20),
+    Encoding::Tilde(21),
+    Encoding::Tilde(23),
+    Encoding::Tilde(24),
+];
+
+/// Final byte of `SS3 x` for keypad keys in application keypad mode.
+const APPLICATION_KEYPAD: &[(char, u8)] = &[
+    ('0', b'p'),
+    ('1', b'q'),
+    ('2', b'r'),
+    ('3', b's'),
+    ('4', b't'),
+    ('5', b'u'),
+    ('6', b'v'),
+    ('7', b'w'),
+    ('8', b'x'),
+    ('9', b'y'),
+    ('.', b'n'),
+    ('+', b'k'),
+    ('-', b'm'),
+    ('*', b'j'),
+    ('/', b'o'),
+    ('=', b'X'),
+];
+
+/// Translate a key event into the bytes an xterm would send for it, or
+/// into kitty keyboard protocol sequences once the remote application
+/// enabled them. Returns `None` for key releases nobody asked for and keys
+/// with no terminal encoding.
+pub fn encode(key: &KeyEvent, modes: &RemoteModes) -> Option<Vec<u8>> {
+    if key.kind == KeyEventKind::Release && modes.kitty_keyboard & kitty::REPORT_EVENT_TYPES == 0 {
+        return None;
+    }
+    if modes.kitty_keyboard != 0 {
+        if let Some(bytes) = kitty::encode(key, modes.kitty_keyboard) {
+            return Some(bytes);
+        }
+    }
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    let mods = key.modifiers;
+
+    if key.state.contains(KeyEventState::KEYPAD) && modes.application_keypad {
+        let keypad = match key.code {
+            KeyCode::Enter => Some(b'M'),
+            KeyCode::Char(c) =>
+                APPLICATION_KEYPAD.iter()
+                    .find(|(k, _)| *k == c)
+                    .map(|(_, f)| *f),
+            _ => None,
+        };
+        if let Some(final_byte) = keypad {
+            return Some(vec![ESC, b'O', final_byte]);
+        }
+    }
+
+    if let Some((_, encoding)) = NAVIGATION_KEYS.iter().find(|(code, _)| *code == key.code) {
+        return Some(sequence(*encoding, mods, modes));
+    }
+
+    match key.code {
+        KeyCode::F(n @ 1..=12) => Some(sequence(FUNCTION_KEYS[n as usize - 1], mods, modes)),
+        KeyCode::F(n @ 13..=24) => {
+            Some(sequence(FUNCTION_KEYS[n as usize - 13], mods | KeyModifiers::SHIFT, modes))
+        }
+        KeyCode::BackTab => {
+            let mods = mods - KeyModifiers::SHIFT;
+            if mods.is_empty() {
+                Some(b"\x1b[Z".to_vec())
+            } else if modes.modify_other_keys == 0 {
+                Some(with_alt(b"\x1b[Z".to_vec(), mods))
+            } else {
+                Some(modify_other_keys(b'\t' as u32, mods | KeyModifiers::SHIFT))
+            }
+        }
+        KeyCode::Enter => Some(control_key(b'\r', mods, modes)),
+        KeyCode::Tab => Some(control_key(b'\t', mods, modes)),
+        KeyCode::Backspace => Some(control_key(0x7f, mods, modes)),
+        KeyCode::Esc => Some(control_key(ESC, mods, modes)),
+        KeyCode::Null => Some(vec![0]),
+        KeyCode::Char(c) => Some(character(c, mods, modes)),
+        _ => None,
+    }
+}
+
+/// Character a key press stands for in the escape menu: the text it types,
+/// or the control code for Ctrl combinations and editing keys.
+pub fn menu_unit(key: &KeyEvent) -> Option<char> {
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    match key.code {
+        KeyCode::Enter => Some('\r'),
+        KeyCode::Esc => Some('\x1b'),
+        KeyCode::Backspace => Some('\x7f'),
+        KeyCode::Char(c) if ctrl => control_code(c).map(char::from),
+        KeyCode::Char(c) => Some(c),
+        _ => None,
+    }
+}
+
+/// xterm modifier parameter: 1 + Shift(1) + Alt(2) + Ctrl(4) + Meta(8).
+fn modifier_param(mods: KeyModifiers) -> u8 {
+    let mut param = 1;
+    if mods.contains(KeyModifiers::SHIFT) {
+        param += 1;
+    }
+    if mods.contains(KeyModifiers::ALT) {
+        param += 2;
+    }
+    if mods.contains(KeyModifiers::CONTROL) {
+        param += 4;
+    }
+    if mods.intersects(KeyModifiers::META | KeyModifiers::SUPER) {
+        param += 8;
+    }
+    param
+}
+
+fn sequence(encoding: Encoding, mods: KeyModifiers, modes: &RemoteModes) -> Vec<u8> {
+    let param = modifier_param(mods);
+    match encoding {
+        Encoding::Cursor(f) if param == 1 => {
+            let introducer = if modes.application_cursor { b'O' } else { b'[' };
+            vec![ESC, introducer, f]
+        }
+        Encoding::Ss3(f) if param == 1 => vec![ESC, b'O', f],
+        Encoding::Cursor(f) | Encoding::Ss3(f) => format!("\x1b[1;{}", param).bytes().chain([f]).collect(),
+        Encoding::Tilde(n) if param == 1 => format!("\x1b[{}~", n).into_bytes(),
+        Encoding::Tilde(n) => format!("\x1b[{};{}~", n, param).into_bytes(),
+    }
+}
+
+/// modifyOtherKeys form, `CSI 27 ; m ; code ~`, for combinations that have
+/// no legacy encoding.
+fn modify_other_keys(code: u32, mods: KeyModifiers) -> Vec<u8> {
+    format!("\x1b[27;{};{}~", modifier_param(mods), code).into_bytes()
+}
+
+/// `bytes` prefixed with ESC when Alt is held, as xterm's metaSendsEscape.
+fn with_alt(bytes: Vec<u8>, mods: KeyModifiers) -> Vec<u8> {
+    if mods.contains(KeyModifiers::ALT) { [&[ESC][..], &bytes].concat() } else { bytes }
+}
+
+/// Enter, Tab, Backspace and Escape. Combinations without a legacy encoding
+/// lose their modifiers unless the remote application enabled
+/// modifyOtherKeys, like in xterm.
+fn control_key(byte: u8, mods: KeyModifiers, modes: &RemoteModes) -> Vec<u8> {
+    let rest = mods - KeyModifiers::ALT;
+    let legacy = rest.is_empty() || rest == KeyModifiers::SHIFT || (rest == KeyModifiers::CONTROL && byte == 0x7f);
+    if !legacy && modes.modify_other_keys != 0 {
+        return modify_other_keys(byte as u32, mods);
+    }
+    // Ctrl+Backspace sends BS
+    let base = if byte == 0x7f && rest.contains(KeyModifiers::CONTROL) { 0x08 } else { byte };
+    with_alt(vec![base], mods)
+}
+
+/// Control code produced by Ctrl+`c` on a US layout, if there is one.
+fn control_code(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' => Some((c as u8) - b'a' + 1),
+        'A'..='Z' => Some((c as u8) - b'A' + 1),
+        '@' | ' ' | '2' => Some(0),
+        '[' | '3' => Some(0x1b),
+        '\\' | '4' => Some(0x1c),
+        ']' | '5' => Some(0x1d),
+        '^' | '~' | '6' => Some(0x1e),
+        '_' | '-' | '/' | '7' => Some(0x1f),
+        '?' | '8' => Some(0x7f),
+        _ => None,
+    }
+}
+
+fn character(c: char, mods: KeyModifiers, modes: &RemoteModes) -> Vec<u8> {
+    let mut utf8 = [0u8; 4];
+    let text = c.encode_utf8(&mut utf8).as_bytes();
+    let rest = mods - KeyModifiers::SHIFT;
+    if rest.is_empty() {
+        return text.to_vec();
+    }
+
+    if modes.modify_other_keys < 2 {
+        let legacy = match rest - KeyModifiers::ALT {
+            m if m.is_empty() => Some(text.to_vec()),
+            KeyModifiers::CONTROL => control_code(c).map(|code| vec![code]),
+            _ => None,
+        };
+        // Without modifyOtherKeys there is nothing to tell the modifiers
+        // apart, so Ctrl+1 types 1 as in xterm
+        let fallback = || {
+            let control = rest.contains(KeyModifiers::CONTROL).then(|| control_code(c)).flatten();
+            control.map(|code| vec![code]).unwrap_or_else(|| text.to_vec())
+        };
+        match legacy {
+            Some(bytes) => return with_alt(bytes, mods),
+            None if modes.modify_other_keys == 0 => return with_alt(fallback(), mods),
+            None => {}
+        }
+    }
+
+    modify_other_keys(c as u32, mods)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONE: KeyModifiers = KeyModifiers::NONE;
+    const SHIFT: KeyModifiers = KeyModifiers::SHIFT;
+    const CTRL: KeyModifiers = KeyModifiers::CONTROL;
+    const ALT: KeyModifiers = KeyModifiers::ALT;
+
+    fn press(code: KeyCode, mods: KeyModifiers, modes: &RemoteModes) -> Vec<u8> {
+        encode(&KeyEvent::new(code, mods), modes).unwrap()
+    }
+
+    fn other_keys(level: u8) -> RemoteModes {
+        RemoteModes { modify_other_keys: level, ..RemoteModes::default() }
+    }
+
+    #[test]
+    fn cursor_keys_follow_decckm() {
+        let normal = RemoteModes::default();
+        let application = RemoteModes { application_cursor: true, ..RemoteModes::default() };
+        assert_eq!(press(KeyCode::Up, NONE, &normal), b"\x1b[A");
+        assert_eq!(press(KeyCode::Up, NONE, &application), b"\x1bOA");
+        assert_eq!(press(KeyCode::End, NONE, &application), b"\x1bOF");
+        // Modified cursor keys are the same in both modes
+        assert_eq!(press(KeyCode::Left, CTRL, &normal), b"\x1b[1;5D");
+        assert_eq!(press(KeyCode::Left, CTRL, &application), b"\x1b[1;5D");
+        assert_eq!(press(KeyCode::Home, SHIFT | ALT, &normal), b"\x1b[1;4H");
+    }
+
+    #[test]
+    fn editing_and_function_keys() {
+        let modes = RemoteModes::default();
+        assert_eq!(press(KeyCode::Delete, NONE, &modes), b"\x1b[3~");
+        assert_eq!(press(KeyCode::PageUp, ALT, &modes), b"\x1b[5;3~");
+        assert_eq!(press(KeyCode::F(1), NONE, &modes), b"\x1bOP");
+        assert_eq!(press(KeyCode::F(1), SHIFT, &modes), b"\x1b[1;2P");
+        assert_eq!(press(KeyCode::F(5), NONE, &modes), b"\x1b[15~");
+        assert_eq!(press(KeyCode::F(12), CTRL, &modes), b"\x1b[24;5~");
+        assert_eq!(press(KeyCode::F(13), NONE, &modes), b"\x1b[1;2P");
+        assert_eq!(press(KeyCode::F(17), NONE, &modes), b"\x1b[15;2~");
+        assert_eq!(encode(&KeyEvent::new(KeyCode::F(25), NONE), &modes), None);
+    }
+
+    #[test]
+    fn keypad_follows_deckpam() {
+        let keypad = |code| KeyEvent::new_with_kind_and_state(code, NONE, KeyEventKind::Press, KeyEventState::KEYPAD);
+        let numeric = RemoteModes::default();
+        let application = RemoteModes { application_keypad: true, ..RemoteModes::default() };
+        assert_eq!(encode(&keypad(KeyCode::Char('5')), &numeric).unwrap(), b"5");
+        assert_eq!(encode(&keypad(KeyCode::Char('5')), &application).unwrap(), b"\x1bOu");
+        assert_eq!(encode(&keypad(KeyCode::Char('+')), &application).unwrap(), b"\x1bOk");
+        assert_eq!(encode(&keypad(KeyCode::Enter), &application).unwrap(), b"\x1bOM");
+        // The main keyboard is not affected
+        assert_eq!(press(KeyCode::Char('5'), NONE, &application), b"5");
+    }
+
+    #[test]
+    fn control_keys_fall_back_to_legacy_bytes() {
+        let modes = other_keys(0);
+        assert_eq!(press(KeyCode::Enter, NONE, &modes), b"\r");
+        assert_eq!(press(KeyCode::Enter, ALT, &modes), b"\x1b\r");
+        assert_eq!(press(KeyCode::Enter, CTRL, &modes), b"\r");
+        assert_eq!(press(KeyCode::Tab, CTRL | SHIFT, &modes), b"\t");
+        assert_eq!(press(KeyCode::Backspace, NONE, &modes), b"\x7f");
+        assert_eq!(press(KeyCode::Backspace, CTRL, &modes), b"\x08");
+        assert_eq!(press(KeyCode::Backspace, ALT, &modes), b"\x1b\x7f");
+        assert_eq!(press(KeyCode::Esc, NONE, &modes), b"\x1b");
+        assert_eq!(press(KeyCode::BackTab, SHIFT, &modes), b"\x1b[Z");
+        assert_eq!(press(KeyCode::BackTab, SHIFT | ALT, &modes), b"\x1b\x1b[Z");
+    }
+
+    #[test]
+    fn control_keys_with_modify_other_keys() {
+        let modes = other_keys(1);
+        assert_eq!(press(KeyCode::Enter, CTRL, &modes), b"\x1b[27;5;13~");
+        assert_eq!(press(KeyCode::Tab, CTRL | SHIFT, &modes), b"\x1b[27;6;9~");
+        assert_eq!(press(KeyCode::BackTab, SHIFT | CTRL, &modes), b"\x1b[27;6;9~");
+        // Legacy combinations keep their legacy encoding
+        assert_eq!(press(KeyCode::Enter, ALT, &modes), b"\x1b\r");
+        assert_eq!(press(KeyCode::Backspace, CTRL, &modes), b"\x08");
+        assert_eq!(press(KeyCode::BackTab, SHIFT, &modes), b"\x1b[Z");
+    }
+
+    #[test]
+    fn characters() {
+        let modes = other_keys(0);
+        assert_eq!(press(KeyCode::Char('a'), NONE, &modes), b"a");
+        assert_eq!(press(KeyCode::Char('A'), SHIFT, &modes), b"A");
+        assert_eq!(press(KeyCode::Char('é'), NONE, &modes), "é".as_bytes());
+        assert_eq!(press(KeyCode::Char('c'), CTRL, &modes), b"\x03");
+        assert_eq!(press(KeyCode::Char(' '), CTRL, &modes), b"\x00");
+        assert_eq!(press(KeyCode::Char('['), CTRL, &modes), b"\x1b");
+        assert_eq!(press(KeyCode::Char('x'), ALT, &modes), b"\x1bx");
+        assert_eq!(press(KeyCode::Char('a'), CTRL | ALT, &modes), b"\x1b\x01");
+        // No legacy encoding, the modifiers are lost as in xterm
+        assert_eq!(press(KeyCode::Char('1'), CTRL, &modes), b"1");
+        assert_eq!(press(KeyCode::Char('a'), CTRL | SHIFT, &modes), b"\x01");
+    }
+
+    #[test]
+    fn characters_with_modify_other_keys() {
+        let level1 = other_keys(1);
+        assert_eq!(press(KeyCode::Char('c'), CTRL, &level1), b"\x03");
+        assert_eq!(press(KeyCode::Char('1'), CTRL, &level1), b"\x1b[27;5;49~");
+        let level2 = other_keys(2);
+        assert_eq!(press(KeyCode::Char('c'), CTRL, &level2), b"\x1b[27;5;99~");
+        assert_eq!(press(KeyCode::Char('x'), ALT, &level2), b"\x1b[27;3;120~");
+        assert_eq!(press(KeyCode::Char('a'), NONE, &level2), b"a");
+    }
+
+    #[test]
+    fn releases_are_dropped_without_kitty() {
+        let release = KeyEvent::new_with_kind(KeyCode::Char('a'), NONE, KeyEventKind::Release);
+        assert_eq!(encode(&release, &RemoteModes::default()), None);
+    }
+
+    #[test]
+    fn menu_units() {
+        assert_eq!(menu_unit(&KeyEvent::new(KeyCode::Char('q'), NONE)), Some('q'));
+        assert_eq!(menu_unit(&KeyEvent::new(KeyCode::Char('b'), CTRL)), Some('\x02'));
+        assert_eq!(menu_unit(&KeyEvent::new(KeyCode::Enter, NONE)), Some('\r'));
+        assert_eq!(menu_unit(&KeyEvent::new(KeyCode::Up, NONE)), None);
+    }
+}
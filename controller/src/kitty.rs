@@ -0,0 +1,198 @@
+use crossterm::event::{
+    KeyCode,
+    KeyEvent,
+    KeyEventKind,
+    KeyEventState,
+    KeyModifiers,
+    MediaKeyCode,
+    ModifierKeyCode,
+};
+
+/// Progressive enhancement flags of the kitty keyboard protocol.
+pub const DISAMBIGUATE: u8 = 0b0_0001;
+pub const REPORT_EVENT_TYPES: u8 = 0b0_0010;
+pub const REPORT_ALL_KEYS: u8 = 0b0_1000;
+
+/// Flags the controller can honour when the local terminal speaks the
+/// protocol too, and when it only sends legacy sequences (no key release
+/// events can be produced then).
+pub const SUPPORTED_WITH_LOCAL: u8 = DISAMBIGUATE | REPORT_EVENT_TYPES | REPORT_ALL_KEYS;
+pub const SUPPORTED_WITHOUT_LOCAL: u8 = DISAMBIGUATE | REPORT_ALL_KEYS;
+
+/// How a key is reported under the protocol.
+enum Form {
+    /// `CSI code ; mods u`
+    Code(u32),
+    /// `CSI 1 ; mods X`, `CSI X` when unmodified.
+    Letter(u8),
+    /// `CSI n ; mods ~`
+    Tilde(u8),
+}
+
+const KEYPAD_CODES: &[(char, u32)] = &[
+    ('0', 57399),
+    ('1', 57400),
+    ('2', 57401),
+    ('3', 57402),
+    ('4', 57403),
+    ('5', 57404),
+    ('6', 57405),
+    ('7', 57406),
+    ('8', 57407),
+    ('9', 57408),
+    ('.', 57409),
+    ('/', 57410),
+    ('*', 57411),
+    ('-', 57412),
+    ('+', 57413),
+    ('=', 57415),
+];
+
+fn form(key: &KeyEvent) -> Option<Form> {
+    let keypad = key.state.contains(KeyEventState::KEYPAD);
+    let form = match key.code {
+        KeyCode::Char(c) if keypad => {
+            KEYPAD_CODES.iter()
+                .find(|(k, _)| *k == c)
+                .map(|(_, code)| Form::Code(*code))?
+        }
+        KeyCode::Enter if keypad => Form::Code(57414),
+        KeyCode::Char(c) => Form::Code(c.to_lowercase().next().unwrap_or(c) as u32),
+        KeyCode::Esc => Form::Code(27),
+        KeyCode::Enter => Form::Code(13),
+        KeyCode::Tab | KeyCode::BackTab => Form::Code(9),
+        KeyCode::Backspace => Form::Code(127),
+        KeyCode::Insert => Form::Tilde(2),
+        KeyCode::Delete => Form::Tilde(3),
+        KeyCode::PageUp => Form::Tilde(5),
+        KeyCode::PageDown => Form::Tilde(6),
+        KeyCode::Up => Form::Letter(b'A'),
+        KeyCode::Down => Form::Letter(b'B'),
+        KeyCode::Right => Form::Letter(b'C'),
+        KeyCode::Left => Form::Letter(b'D'),
+        KeyCode::Home => Form::Letter(b'H'),
+        KeyCode::End => Form::Letter(b'F'),
+        KeyCode::KeypadBegin => Form::Letter(b'E'),
+        KeyCode::F(n @ 1..=4) => Form::Letter(b"PQRS"[n as usize - 1]),
+        KeyCode::F(n @ 5..=12) => Form::Tilde([15, 17, 18, 19, 20, 21, 23, 24][n as usize - 5]),
+        KeyCode::F(n @ 13..=35) => Form::Code(57376 + (n as u32) - 13),
+        KeyCode::CapsLock => Form::Code(57358),
+        KeyCode::ScrollLock => Form::Code(57359),
+        KeyCode::NumLock => Form::Code(57360),
+        KeyCode::PrintScreen => Form::Code(57361),
+        KeyCode::Pause => Form::Code(57362),
+        KeyCode::Menu => Form::Code(57363),
+        KeyCode::Media(media) => Form::Code(57428 + media_index(media)),
+        KeyCode::Modifier(modifier) => Form::Code(57441 + modifier_index(modifier)),
+        _ => {
+            return None;
+        }
+    };
+    Some(form)
+}
+
+fn media_index(media: MediaKeyCode) -> u32 {
+    match media {
+        MediaKeyCode::Play => 0,
+        MediaKeyCode::Pause => 1,
+        MediaKeyCode::PlayPause => 2,
+        MediaKeyCode::Reverse => 3,
+        MediaKeyCode::Stop => 4,
+        MediaKeyCode::FastForward => 5,
+        MediaKeyCode::Rewind => 6,
+        MediaKeyCode::TrackNext => 7,
+        MediaKeyCode::TrackPrevious => 8,
+        MediaKeyCode::Record => 9,
+        MediaKeyCode::LowerVolume => 10,
+        MediaKeyCode::RaiseVolume => 11,
+        MediaKeyCode::MuteVolume => 12,
+    }
+}
+
+fn modifier_index(modifier: ModifierKeyCode) -> u32 {
+    match modifier {
+        ModifierKeyCode::LeftShift => 0,
+        ModifierKeyCode::LeftControl => 1,
+        ModifierKeyCode::LeftAlt => 2,
+        ModifierKeyCode::LeftSuper => 3,
+        ModifierKeyCode::LeftHyper => 4,
+        ModifierKeyCode::LeftMeta => 5,
+        ModifierKeyCode::RightShift => 6,
+        ModifierKeyCode::RightControl => 7,
+        ModifierKeyCode::RightAlt => 8,
+        ModifierKeyCode::RightSuper => 9,
+        ModifierKeyCode::RightHyper => 10,
+        ModifierKeyCode::RightMeta => 11,
+        ModifierKeyCode::IsoLevel3Shift => 12,
+        ModifierKeyCode::IsoLevel5Shift => 13,
+    }
+}
+
+/// Kitty modifier parameter: 1 + Shift(1) + Alt(2) + Ctrl(4) + Super(8) + Hyper(16) + Meta(32).
+fn modifier_param(mods: KeyModifiers) -> u8 {
+    [
+        (KeyModifiers::SHIFT, 1),
+        (KeyModifiers::ALT, 2),
+        (KeyModifiers::CONTROL, 4),
+        (KeyModifiers::SUPER, 8),
+        (KeyModifiers::HYPER, 16),
+        (KeyModifiers::META, 32),
+    ]
+        .iter()
+        .filter(|(m, _)| mods.contains(*m))
+        .fold(1, |param, (_, bit)| param + bit)
+}
+
+/// Encode a key event under the kitty keyboard protocol with `flags`
+/// active. Returns `None` for keys that keep their legacy encoding, or that
+/// are not reported at all; the caller has already dropped releases the
+/// remote did not ask for.
+pub fn encode(key: &KeyEvent, flags: u8) -> Option<Vec<u8>> {
+    let report_all = flags & REPORT_ALL_KEYS != 0;
+    let event_types = flags & REPORT_EVENT_TYPES != 0;
+    let event = match key.kind {
+        KeyEventKind::Press => 1,
+        KeyEventKind::Repeat if event_types => 2,
+        KeyEventKind::Repeat => 1,
+        KeyEventKind::Release => 3,
+    };
+
+    let mods = if key.code == KeyCode::BackTab {
+        key.modifiers | KeyModifiers::SHIFT
+    } else {
+        key.modifiers
+    };
+    let param = modifier_param(mods);
+    let text_mods = mods - KeyModifiers::SHIFT;
+
+    if !report_all {
+        let legacy = match key.code {
+            KeyCode::Modifier(_) => true,
+            // Text keys keep sending text unless Ctrl/Alt/... is involved
+            KeyCode::Char(_) => text_mods.is_empty() && event != 3,
+            // Enter, Tab and Backspace are only disambiguated when modified
+            KeyCode::Enter | KeyCode::Tab | KeyCode::Backspace => mods.is_empty() && event != 3,
+            KeyCode::BackTab => event != 3,
+            _ => false,
+        };
+        if legacy {
+            return None;
+        }
+    }
+
+    let form = form(key)?;
+    let suffix = match (param, event) {
+        (1, 1) => String::new(),
+        (_, 1) => format!("{}", param),
+        (_, _) => format!("{}:{}", param, event),
+    };
+    let sequence = match form {
+        Form::Code(code) if suffix.is_empty() => format!("\x1b[{}u", code),
+        Form::Code(code) => format!("\x1b[{};{}u", code, suffix),
+        Form::Letter(f) if suffix.is_empty() => format!("\x1b[{}", f as char),
+        Form::Letter(f) => format!("\x1b[1;{}{}", suffix, f as char),
+        Form::Tilde(n) if suffix.is_empty() => format!("\x1b[{}~", n),
+        Form::Tilde(n) => format!("\x1b[{};{}~", n, suffix),
+    };
+    Some(sequence.into_bytes())
+}
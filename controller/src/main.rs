@@ -1,13 +1,58 @@
-use rumqttc::{ AsyncClient, Event, MqttOptions, Packet, QoS };
-use tokio::sync::mpsc;
+use rumqttc::v5::mqttbytes::v5::Packet;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{ AsyncClient, Event, MqttOptions };
+use rumqttc::Outgoing;
+use tokio::sync::{ mpsc, watch };
 use tokio::time::{ sleep, Duration };
 use crossterm::{
-    event::{ self, Event as CrosstermEvent, KeyCode, KeyEvent, KeyModifiers },
-    terminal::{ self, size },
+    event::{
+        self,
+        EnableBracketedPaste,
+        Event as CrosstermEvent,
+        KeyEventKind,
+    },
+    execute,
+    terminal,
 };
-use serde::{ Deserialize, Serialize };
-use std::io::{ self, Write };
-use clap::Parser;
+use std::io;
+use std::sync::{ Arc, Mutex };
+use clap::{ Parser, Subcommand };
+use mqttshell_proto::compress::parse_accept;
+use mqttshell_proto::envelope::{ Body, Format, Kind, Wire };
+use mqttshell_proto::handshake::Capability;
+use mqttshell_proto::session::{ self, SessionOpen };
+
+mod compress;
+mod forward;
+mod guard;
+mod handshake;
+mod input;
+mod keys;
+mod kitty;
+mod menu;
+mod modes;
+mod mouse;
+mod output;
+mod paste;
+mod predict;
+mod proxy;
+mod raw_input;
+mod resize;
+mod socks;
+mod status;
+mod streams;
+mod upload;
+
+use compress::{ Codec, CompressMode };
+use guard::TerminalGuard;
+use handshake::{ Attached, Handshake, WELCOME_TIMEOUT };
+use input::{ InputGate, InputOptions, InputPolicy };
+use menu::{ Controls, EscapeKey, Flow };
+use modes::ModeTracker;
+use output::{ notify, InputEncoder, Output };
+use predict::{ PredictMode, Typed };
+use status::{ Status, StatusMode };
+use streams::StreamMux;
 
 #[derive(Parser, Debug)]
 #[command(name = "mqtt-shell-controller")]
@@ -16,233 +61,641 @@ struct Args {
     #[arg(short, long, default_value = "shell")]
     channel: String,
 
-    #[arg(long, default_value = "127.0.0.1")]
+    #[arg(long, default_value = "127.0.0.1", global = true)]
     host: String,
 
-    #[arg(long, default_value_t = 1883)]
+    #[arg(long, default_value_t = 1883, global = true)]
     port: u16,
+
+    /// Payload encoding on every topic; the agent must use the same
+    #[arg(long, value_enum, default_value_t = Format::Raw, global = true)]
+    format: Format,
+
+    /// Run a local SOCKS5 proxy whose connections are dialled by the agent
+    #[arg(short = 'D', long = "dynamic-forward", value_name = "[BIND:]PORT")]
+    dynamic_forward: Vec<String>,
+
+    /// Forward a local port or socket path to a Unix socket on the agent host
+    #[arg(long = "unix-forward", value_name = "LOCAL:REMOTE_SOCKET")]
+    unix_forward: Vec<String>,
+
+    /// Forward bytes from the local TTY unchanged instead of decoding keys
+    #[arg(long)]
+    raw_input: bool,
+
+    /// Character set used by the remote host, e.g. latin1 or cp1252. Output
+    /// is converted to UTF-8 and input back to this charset
+    #[arg(long, value_name = "LABEL")]
+    remote_charset: Option<String>,
+
+    /// Where to show the channel, connection state, latency and agent status
+    #[arg(long, value_enum, default_value_t = StatusMode::Off)]
+    status: StatusMode,
+
+    /// Key opening the escape menu: a character recognised after Enter
+    /// like ssh's `~`, a prefix such as `ctrl-]`, or `none`
+    #[arg(short, long, default_value = "~", value_name = "KEY")]
+    escape_key: EscapeKey,
+
+    /// What to do with input typed while the broker is unreachable
+    #[arg(long, value_enum, default_value_t = InputPolicy::Queue, value_name = "POLICY")]
+    offline_input: InputPolicy,
+
+    /// Most bytes of input queued while the broker is unreachable
+    #[arg(long, default_value_t = 4096, value_name = "BYTES")]
+    input_queue: usize,
+
+    /// Seconds after which typed input is discarded instead of delivered,
+    /// by the controller and by the broker (MQTT message expiry); 0 never
+    #[arg(long, default_value_t = 10, value_name = "SECS")]
+    input_expiry: u64,
+
+    /// Echo typed characters locally before the remote does, for slow
+    /// links. Ignored with --raw-input
+    #[arg(long, value_enum, default_value_t = PredictMode::Off, value_name = "MODE")]
+    predict: PredictMode,
+
+    /// Payload compression to offer the agent; `off` asks for none
+    #[arg(long, value_enum, default_value_t = CompressMode::Auto, value_name = "MODE")]
+    compress: CompressMode,
+
+    /// Local environment variable to pass to the remote shell, besides
+    /// TERM, COLORTERM, LANG and LC_*; a trailing '*' matches any suffix.
+    /// The agent has to accept it too. Can be repeated
+    #[arg(long = "send-env", value_name = "NAME")]
+    send_env: Vec<String>,
+
+    /// Run one of the agent's profiles instead of its shell
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Run the session as this user, if the agent allows it
+    #[arg(long, value_name = "NAME")]
+    user: Option<String>,
+
+    /// Print diagnostics into the terminal when no status is shown
+    #[arg(short, long)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TerminalResize {
-    rows: u16,
-    cols: u16,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Connect stdin/stdout to a TCP port or Unix socket opened by the agent,
+    /// e.g. `ssh -o ProxyCommand="controller proxy dev42 127.0.0.1:22" dev42`
+    Proxy {
+        channel: String,
+        #[arg(value_name = "HOST:PORT")]
+        target: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Proxy { channel, target }) = &args.command {
+        return proxy::run(channel, target, (&args.host, args.port), args.format).await;
+    }
+
+    let charset = args.remote_charset.as_deref().map(output::parse_charset).transpose()?.flatten();
+
     println!("Starting MQTT Shell Controller with TTY support...");
     println!("📡 Using channel: '{}' at {}:{}", args.channel, args.host, args.port);
 
     let shell_in = format!("{}/in", args.channel);
     let shell_out = format!("{}/out", args.channel);
+    let shell_out_z = format!("{}/out/z", args.channel);
     let shell_status = format!("{}/status", args.channel);
     let shell_resize = format!("{}/resize", args.channel);
+    let shell_welcome = format!("{}/welcome", args.channel);
 
     let mut mqttoptions = MqttOptions::new("controller", &args.host, args.port);
     mqttoptions.set_keep_alive(Duration::from_secs(5));
     let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
 
     client.subscribe(&shell_out, QoS::AtMostOnce).await.unwrap();
+    client.subscribe(&shell_out_z, QoS::AtMostOnce).await.unwrap();
     client.subscribe(&shell_status, QoS::AtMostOnce).await.unwrap();
+    client.subscribe(&shell_welcome, QoS::AtMostOnce).await.unwrap();
     println!("🔍 Controller subscribed to {} and {}", shell_out, shell_status);
 
-    let (cols, rows) = size().unwrap_or((80, 24));
-    let initial_size = TerminalResize { rows, cols };
+    let wire = Arc::new(Wire::new(args.format));
+    let mux = StreamMux::new(client.clone(), &args.channel, Arc::clone(&wire));
+    let forwarding = !args.dynamic_forward.is_empty() || !args.unix_forward.is_empty();
+    if forwarding {
+        mux.subscribe().await?;
+        for spec in &args.dynamic_forward {
+            let (bind_host, bind_port) = forward::parse_bind(spec)?;
+            let listener = tokio::net::TcpListener::bind((bind_host.as_str(), bind_port)).await?;
+            println!("🧦 SOCKS5 proxy listening on {}", listener.local_addr()?);
+            tokio::spawn(socks::serve(Arc::clone(&mux), listener));
+        }
+        for spec in &args.unix_forward {
+            let (listener, target) = forward::bind_unix_forward(spec).await?;
+            println!("🔀 Forwarding {} to {:?}", listener.describe(), target);
+            tokio::spawn(forward::serve(Arc::clone(&mux), listener, target));
+        }
+    }
 
-    let size_json = serde_json::to_string(&initial_size)?;
-    client.publish(&shell_resize, QoS::AtMostOnce, false, size_json).await?;
+    let local_size = resize::current();
 
-    println!("Controller connected. Terminal size: {}x{}", cols, rows);
-    println!("Press Ctrl+Q to exit.");
+    println!("Controller connected. Terminal size: {}x{}", local_size.cols, local_size.rows);
+    println!("Press {} then ? for the escape menu.", args.escape_key.describe());
     println!("You can now use editors like nano, vim, etc.");
 
     terminal::enable_raw_mode()?;
+    // Ask the local terminal whether it speaks the kitty keyboard protocol
+    // before any remote output can reach it.
+    let local_kitty = !args.raw_input && terminal::supports_keyboard_enhancement().unwrap_or(false);
+    let guard = TerminalGuard::install(local_kitty, args.status.reset_sequence());
 
-    let (tx_input, mut rx_input) = mpsc::unbounded_channel::<Vec<u8>>();
-    let (tx_exit, mut rx_exit) = mpsc::unbounded_channel::<()>();
-    let client_input = client.clone();
+    let status = Status {
+        channel: args.channel.clone(),
+        connected: true,
+        ..Status::default()
+    };
+    let predict = if args.raw_input { PredictMode::Off } else { args.predict };
+    let mut output = Output::new(charset, args.status, status, predict, args.verbose);
+    output.start(local_size)?;
+    let output = Arc::new(Mutex::new(output));
+    let codec = Arc::new(Mutex::new(Codec::new(args.compress)));
+    let open = SessionOpen {
+        size: local_size,
+        env: session::select(std::env::vars(), &args.send_env),
+        profile: args.profile.clone(),
+        user: args.user.clone(),
+    };
+    let handshake = Arc::new(
+        Mutex::new(
+            Handshake::new(
+                vec![Capability::Compression, Capability::Forwarding, Capability::Snapshot, Capability::Session],
+                &open
+            )
+        )
+    );
+    let session = Session {
+        output: Arc::clone(&output),
+        codec: Arc::clone(&codec),
+        handshake: Arc::clone(&handshake),
+        wire: Arc::clone(&wire),
+        open: Arc::new(open),
+    };
+    // The session request, size, compression offer and refresh follow once
+    // the agent answered
+    tokio::spawn(attach(client.clone(), session.clone(), args.channel.clone()));
 
-    tokio::spawn(async move {
-        while let Some(input) = rx_input.recv().await {
-            if let Err(e) = client_input.publish(&shell_in, QoS::AtMostOnce, false, input).await {
-                eprintln!("Error sending input: {:?}", e);
-            }
-        }
-    });
+    let (tx_input, rx_input) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (tx_exit, mut rx_exit) = mpsc::unbounded_channel::<String>();
+    // Input is held until the broker connection is up
+    let (tx_connected, rx_connected) = watch::channel(false);
+
+    let input_options = InputOptions {
+        policy: args.offline_input,
+        queue_limit: args.input_queue,
+        expiry: Duration::from_secs(args.input_expiry),
+    };
+    let input_topic = shell_in.clone();
+    let input_expiry = input_options.expiry;
+    tokio::spawn(
+        input::publish(
+            client.clone(),
+            shell_in,
+            rx_input,
+            rx_connected.clone(),
+            input_options,
+            (charset.map(InputEncoder::new), Arc::clone(&codec), Arc::clone(&wire)),
+            Arc::clone(&output)
+        )
+    );
+    let mut gate = InputGate::new(args.offline_input, rx_connected);
+
+    let (kitty_supported, local_kitty_flags) = if local_kitty {
+        (kitty::SUPPORTED_WITH_LOCAL, kitty::SUPPORTED_WITH_LOCAL)
+    } else {
+        (kitty::SUPPORTED_WITHOUT_LOCAL, 0)
+    };
+    let remote_modes = Arc::new(Mutex::new(ModeTracker::new(kitty_supported)));
 
     let tx_exit_clone = tx_exit.clone();
+    let mux_dispatch = Arc::clone(&mux);
+    let output_modes = Arc::clone(&remote_modes);
+    let raw_input = args.raw_input;
+    let tx_replies = tx_input.clone();
+    let event_output = Arc::clone(&output);
+    let event_client = client.clone();
+    let event_codec = Arc::clone(&codec);
+    let event_handshake = Arc::clone(&handshake);
+    let event_wire = Arc::clone(&wire);
+    let event_session = session.clone();
+    let channel = args.channel.clone();
     tokio::spawn(async move {
+        let mut ping_sent = None;
+        let mut outage: Option<std::time::Instant> = None;
+        let mut connected_before = false;
         loop {
             match eventloop.poll().await {
                 Ok(Event::Incoming(Packet::Publish(p))) => {
-                    match p.topic.as_str() {
-                        topic if topic == shell_out => {
-                            print!("{}", String::from_utf8_lossy(&p.payload));
-                            let _ = io::stdout().flush();
+                    let topic = String::from_utf8_lossy(&p.topic);
+                    match topic.as_ref() {
+                        topic if topic == shell_out || topic == shell_out_z => {
+                            let payload = match event_wire.decode_data(&p.payload) {
+                                Ok(payload) => payload,
+                                Err(e) => {
+                                    notify(&event_output, format!("Invalid output: {}", e));
+                                    continue;
+                                }
+                            };
+                            let payload = if topic == shell_out_z {
+                                let opened = match event_codec.lock() {
+                                    Ok(mut codec) => codec.open_output(&payload),
+                                    Err(_) => Ok(None),
+                                };
+                                match opened {
+                                    Ok(Some(payload)) => payload,
+                                    Ok(None) => continue,
+                                    Err(e) => {
+                                        notify(&event_output, format!("Compressed output lost: {}", e));
+                                        tokio::spawn(
+                                            announce(
+                                                event_client.clone(),
+                                                event_session.clone(),
+                                                channel.clone()
+                                            )
+                                        );
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                payload
+                            };
+                            let (overrides, replies) = match output_modes.lock() {
+                                Ok(mut tracker) if !raw_input => {
+                                    tracker.feed(&payload);
+                                    let overrides = tracker
+                                        .take_local_sync_needed()
+                                        .then(|| tracker.modes.local_overrides(local_kitty_flags));
+                                    (overrides, tracker.take_replies())
+                                }
+                                _ => (None, Vec::new()),
+                            };
+                            if let Ok(mut output) = event_output.lock() {
+                                let _ = output.write(&payload);
+                                if let Some(overrides) = overrides {
+                                    let _ = output.write_local(&overrides);
+                                }
+                                let _ = output.flush();
+                            }
+                            if !replies.is_empty() {
+                                let _ = tx_replies.send(replies);
+                            }
                         }
                         topic if topic == shell_status => {
-                            let status = String::from_utf8_lossy(&p.payload);
-                            println!("📡 Status received: '{}'", status);
-                            if status == "shell_exited" {
-                                println!("🎉 Received shell_exited - sending exit signal");
-                                match tx_exit_clone.send(()) {
-                                    Ok(_) => println!("✅ Exit signal sent successfully"),
-                                    Err(e) => println!("❌ Error sending exit signal: {:?}", e),
+                            let status = match event_wire.decode_text(Kind::Status, &p.payload) {
+                                Ok(status) => status,
+                                Err(e) => {
+                                    notify(&event_output, format!("Invalid status: {}", e));
+                                    continue;
                                 }
+                            };
+                            let accepted = parse_accept(&status);
+                            if let Ok(mut output) = event_output.lock() {
+                                match status.as_str() {
+                                    "echo_on" | "echo_off" => {
+                                        let _ = output.set_echo(status == "echo_on");
+                                    }
+                                    _ if accepted.is_some() => {
+                                        if let Ok(mut codec) = event_codec.lock() {
+                                            codec.accept(accepted.flatten());
+                                            output.status.compression = codec.summary();
+                                        }
+                                        let _ = output.status_changed();
+                                    }
+                                    // Reported below, the agent's state is unchanged
+                                    _ if status.starts_with("limit_exceeded ") => {}
+                                    _ => {
+                                        output.status.agent = Some(status.clone());
+                                        let _ = output.status_changed();
+                                    }
+                                }
+                            }
+                            if let Some(limit) = status.strip_prefix("limit_exceeded ") {
+                                notify(&event_output, format!("Session limit exceeded: {}", limit));
+                            }
+                            if status == "agent_online" {
+                                tokio::spawn(
+                                    attach(event_client.clone(), event_session.clone(), channel.clone())
+                                );
+                            }
+                            if status == "shell_exited" {
+                                let _ = tx_exit_clone.send("🎉 Exit signal received from remote shell".to_string());
                                 break;
-                            } else {
-                                println!("🔍 Status ignored: '{}'", status);
                             }
                         }
+                        topic if topic == shell_welcome => {
+                            let welcome = match event_wire.decode_welcome(&p.payload) {
+                                Ok(welcome) => welcome,
+                                Err(e) => {
+                                    notify(&event_output, format!("Invalid welcome: {}", e));
+                                    continue;
+                                }
+                            };
+                            let version = welcome.version;
+                            let attached = match event_handshake.lock() {
+                                Ok(mut handshake) => handshake.welcome(welcome),
+                                Err(_) => Attached::Other,
+                            };
+                            match attached {
+                                Attached::Other => {}
+                                Attached::Refused(error) => {
+                                    let reason = format!("❌ Agent refused the session: {}", status::printable(&error));
+                                    let _ = tx_exit_clone.send(reason);
+                                    break;
+                                }
+                                Attached::Accepted(common) => {
+                                    let names: Vec<&str> = common.iter().map(Capability::name).collect();
+                                    notify(
+                                        &event_output,
+                                        format!("Agent speaks protocol {} ({})", version, names.join(", "))
+                                    );
+                                    if forwarding && !common.contains(&Capability::Forwarding) {
+                                        notify(&event_output, "The agent does not support forwarding".to_string());
+                                    }
+                                    tokio::spawn(
+                                        announce(event_client.clone(), event_session.clone(), channel.clone())
+                                    );
+                                }
+                            }
+                        }
+                        topic if mux_dispatch.dispatch(topic, &p.payload) => {}
                         _ => {
-                            println!("❓ Unknown topic: '{}'", p.topic);
+                            notify(&event_output, format!("Unknown topic: '{}'", topic));
                         }
                     }
                 }
-                Ok(Event::Incoming(Packet::ConnAck(_))) => {}
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    let discarded = outage
+                        .take()
+                        .map(|since| {
+                            input::discard_stale(&mut eventloop.pending, &input_topic, since.elapsed(), input_expiry)
+                        })
+                        .unwrap_or(0);
+                    if let Ok(mut output) = event_output.lock() {
+                        output.status.notice = None;
+                        let _ = output.set_connected(true);
+                    }
+                    if discarded > 0 {
+                        notify(&event_output, format!("Discarded {} input messages typed before the outage", discarded));
+                    }
+                    tx_connected.send_replace(true);
+                    if std::mem::replace(&mut connected_before, true) {
+                        tokio::spawn(
+                            resync(
+                                event_client.clone(),
+                                Arc::clone(&mux_dispatch),
+                                event_session.clone(),
+                                channel.clone()
+                            )
+                        );
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::PingReq)) => {
+                    ping_sent = Some(std::time::Instant::now());
+                }
+                Ok(Event::Incoming(Packet::PingResp(_))) => {
+                    if let (Some(sent), Ok(mut output)) = (ping_sent.take(), event_output.lock()) {
+                        output.status.latency = Some(sent.elapsed());
+                        // Ratios change with every message, they are only
+                        // shown as often as the latency
+                        if let Ok(codec) = event_codec.lock() {
+                            output.status.compression = codec.summary();
+                        }
+                        let _ = output.status_changed();
+                    }
+                }
                 Ok(_) => {}
                 Err(e) => {
-                    eprintln!("MQTT Error: {:?}", e);
+                    outage.get_or_insert_with(std::time::Instant::now);
+                    tx_connected.send_replace(false);
+                    if let Ok(mut output) = event_output.lock() {
+                        let _ = output.set_connected(false);
+                        let _ = output.notify(format!("MQTT Error: {:?}", e));
+                    }
                     sleep(Duration::from_secs(1)).await;
                 }
             }
         }
     });
 
-    let client_resize = client.clone();
-    tokio::spawn(async move {
-        let mut last_size = (cols, rows);
-        loop {
-            tokio::time::sleep(Duration::from_millis(200)).await;
-            if let Ok((new_cols, new_rows)) = size() {
-                if (new_cols, new_rows) != last_size {
-                    last_size = (new_cols, new_rows);
-                    let resize_data = TerminalResize {
-                        rows: new_rows,
-                        cols: new_cols,
-                    };
-                    if let Ok(json) = serde_json::to_string(&resize_data) {
-                        let _ = client_resize.publish(
-                            &shell_resize,
-                            QoS::AtMostOnce,
-                            false,
-                            json
-                        ).await;
-                    }
+    if predict != PredictMode::Off {
+        let output = Arc::clone(&output);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(Duration::from_millis(50));
+            loop {
+                ticks.tick().await;
+                if let Ok(mut output) = output.lock() {
+                    let _ = output.expire_predictions();
                 }
             }
-        }
-    });
+        });
+    }
 
-    println!("🔍 Main loop started - press Ctrl+Q to exit manually");
-    loop {
-        if rx_exit.try_recv().is_ok() {
-            println!("\r\n🎉 Exit signal received from remote shell");
-            break;
-        }
+    tokio::spawn(
+        resize::watch(client.clone(), Arc::clone(&wire), shell_resize, local_size, Arc::clone(&output))
+    );
 
-        if event::poll(Duration::from_millis(10))? {
-            match event::read()? {
-                CrosstermEvent::Key(
-                    KeyEvent { code: KeyCode::Char('q'), modifiers: KeyModifiers::CONTROL, .. },
-                ) => {
-                    break;
-                }
-                CrosstermEvent::Key(
-                    KeyEvent { code: KeyCode::Char('c'), modifiers: KeyModifiers::CONTROL, .. },
-                ) => {
-                    let _ = tx_input.send(vec![3]);
-                }
-                CrosstermEvent::Key(
-                    KeyEvent { code: KeyCode::Char('z'), modifiers: KeyModifiers::CONTROL, .. },
-                ) => {
-                    let _ = tx_input.send(vec![26]);
-                }
-                CrosstermEvent::Key(
-                    KeyEvent { code: KeyCode::Char(c), modifiers: KeyModifiers::NONE, .. },
-                ) => {
-                    let mut bytes = [0u8; 4];
-                    let encoded = c.encode_utf8(&mut bytes);
-                    let _ = tx_input.send(encoded.bytes().collect());
-                }
-                CrosstermEvent::Key(
-                    KeyEvent { code: KeyCode::Char(c), modifiers: KeyModifiers::SHIFT, .. },
-                ) => {
-                    let mut bytes = [0u8; 4];
-                    let encoded = c.encode_utf8(&mut bytes);
-                    let _ = tx_input.send(encoded.bytes().collect());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Enter, .. }) => {
-                    let _ = tx_input.send(vec![b'\r']);
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Backspace, .. }) => {
-                    let _ = tx_input.send(vec![127]);
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Tab, .. }) => {
-                    let _ = tx_input.send(vec![b'\t']);
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Up, .. }) => {
-                    let _ = tx_input.send(b"\x1b[A".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Down, .. }) => {
-                    let _ = tx_input.send(b"\x1b[B".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Right, .. }) => {
-                    let _ = tx_input.send(b"\x1b[C".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Left, .. }) => {
-                    let _ = tx_input.send(b"\x1b[D".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Home, .. }) => {
-                    let _ = tx_input.send(b"\x1b[H".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::End, .. }) => {
-                    let _ = tx_input.send(b"\x1b[F".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::PageUp, .. }) => {
-                    let _ = tx_input.send(b"\x1b[5~".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::PageDown, .. }) => {
-                    let _ = tx_input.send(b"\x1b[6~".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Delete, .. }) => {
-                    let _ = tx_input.send(b"\x1b[3~".to_vec());
-                }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::Insert, .. }) => {
-                    let _ = tx_input.send(b"\x1b[2~".to_vec());
+    let mut controls = Controls::new(
+        args.escape_key,
+        client.clone(),
+        Arc::clone(&wire),
+        Arc::clone(&output),
+        Arc::clone(&mux),
+        &args.channel
+    );
+    if args.raw_input {
+        raw_input::run(controls, tx_input.clone(), &mut rx_exit, gate).await;
+    } else {
+        execute!(io::stdout(), EnableBracketedPaste)?;
+        loop {
+            if let Ok(reason) = rx_exit.try_recv() {
+                println!("\r\n{}", reason);
+                break;
+            }
+            if !gate.is_open() {
+                // Keystrokes wait in the terminal until reconnected
+                tokio::select! {
+                    _ = gate.changed() => {}
+                    _ = sleep(Duration::from_millis(10)) => {}
                 }
-                CrosstermEvent::Key(KeyEvent { code: KeyCode::F(n), .. }) => {
-                    let seq = match n {
-                        1 => b"\x1bOP".to_vec(),
-                        2 => b"\x1bOQ".to_vec(),
-                        3 => b"\x1bOR".to_vec(),
-                        4 => b"\x1bOS".to_vec(),
-                        5 => b"\x1b[15~".to_vec(),
-                        6 => b"\x1b[17~".to_vec(),
-                        7 => b"\x1b[18~".to_vec(),
-                        8 => b"\x1b[19~".to_vec(),
-                        9 => b"\x1b[20~".to_vec(),
-                        10 => b"\x1b[21~".to_vec(),
-                        11 => b"\x1b[23~".to_vec(),
-                        12 => b"\x1b[24~".to_vec(),
-                        _ => {
+                continue;
+            }
+
+            if event::poll(Duration::from_millis(10))? {
+                match event::read()? {
+                    CrosstermEvent::Key(key) => {
+                        let modes = remote_modes
+                            .lock()
+                            .map(|tracker| tracker.modes)
+                            .unwrap_or_default();
+                        let bytes = keys::encode(&key, &modes);
+                        if key.kind == KeyEventKind::Release {
+                            if let Some(bytes) = bytes {
+                                let _ = tx_input.send(bytes);
+                            }
                             continue;
                         }
-                    };
-                    let _ = tx_input.send(seq);
+                        match controls.input(keys::menu_unit(&key), bytes.unwrap_or_default()).await {
+                            Flow::Send(bytes) if !bytes.is_empty() => {
+                                let _ = tx_input.send(bytes);
+                                if let Ok(mut output) = output.lock() {
+                                    let _ = output.predict(Typed::from_key(&key));
+                                }
+                            }
+                            Flow::Send(_) => {}
+                            Flow::Exit => {
+                                break;
+                            }
+                        }
+                    }
+                    CrosstermEvent::Paste(text) => {
+                        let bracketed = remote_modes
+                            .lock()
+                            .map(|tracker| tracker.modes.bracketed_paste)
+                            .unwrap_or(false);
+                        if let Ok(mut output) = output.lock() {
+                            let _ = output.predict(Typed::Other);
+                        }
+                        let bytes = paste::encode(&text, bracketed);
+                        for chunk in bytes.chunks(paste::MAX_PASTE_MESSAGE) {
+                            let _ = tx_input.send(chunk.to_vec());
+                        }
+                    }
+                    focus @ (CrosstermEvent::FocusGained | CrosstermEvent::FocusLost) => {
+                        let focus_events = remote_modes
+                            .lock()
+                            .map(|tracker| tracker.modes.focus_events)
+                            .unwrap_or(false);
+                        if focus_events {
+                            let report = if focus == CrosstermEvent::FocusGained { "\x1b[I" } else { "\x1b[O" };
+                            let _ = tx_input.send(report.as_bytes().to_vec());
+                        }
+                    }
+                    CrosstermEvent::Mouse(mouse_event) => {
+                        let modes = remote_modes
+                            .lock()
+                            .map(|tracker| tracker.modes)
+                            .unwrap_or_default();
+                        if let Some(bytes) = mouse::encode(&mouse_event, &modes) {
+                            let _ = tx_input.send(bytes);
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
-        }
 
-        tokio::time::sleep(Duration::from_millis(1)).await;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
     }
 
-    terminal::disable_raw_mode()?;
+    if let Ok(mut output) = output.lock() {
+        output.stop()?;
+    }
+    drop(guard);
     println!("\rController disconnected. Terminal restored.");
 
     Ok(())
 }
+
+/// Shared state of the session the background tasks below work on.
+#[derive(Clone)]
+struct Session {
+    output: Arc<Mutex<Output>>,
+    codec: Arc<Mutex<Codec>>,
+    handshake: Arc<Mutex<Handshake>>,
+    wire: Arc<Wire>,
+    /// Session request to send, with the size as of startup.
+    open: Arc<SessionOpen>,
+}
+
+/// Restore what a reconnect with a clean session lost: the subscriptions,
+/// then everything [`attach`] sets up, as the agent may have restarted
+/// meanwhile.
+async fn resync(
+    client: AsyncClient,
+    mux: Arc<StreamMux>,
+    session: Session,
+    channel: String
+) -> anyhow::Result<()> {
+    client.subscribe(format!("{}/out", channel), QoS::AtMostOnce).await?;
+    client.subscribe(format!("{}/out/z", channel), QoS::AtMostOnce).await?;
+    client.subscribe(format!("{}/status", channel), QoS::AtMostOnce).await?;
+    client.subscribe(format!("{}/welcome", channel), QoS::AtMostOnce).await?;
+    mux.resubscribe().await?;
+    attach(client, session, channel).await
+}
+
+/// Say hello to the agent. The event loop announces the session once the
+/// welcome arrives; an agent that stays silent is taken for one that
+/// predates the handshake, and gets the announcement without any of the
+/// optional features.
+async fn attach(client: AsyncClient, session: Session, channel: String) -> anyhow::Result<()> {
+    let Ok((attempt, hello)) = session.handshake.lock().map(|mut handshake| handshake.hello()) else {
+        return Ok(());
+    };
+    let payload = session.wire.encode(Body::Hello(hello));
+    client.publish(format!("{}/hello", channel), QoS::AtMostOnce, false, payload).await?;
+    sleep(WELCOME_TIMEOUT).await;
+    let unanswered = session.handshake
+        .lock()
+        .map(|mut handshake| handshake.unanswered(attempt))
+        .unwrap_or(false);
+    if unanswered {
+        notify(&session.output, "No welcome from the agent, assuming an older one".to_string());
+        announce(client, session, channel).await?;
+    }
+    Ok(())
+}
+
+/// Ask a waiting agent to open the session; it comes back online with the
+/// shell and is attached again. An agent running a shell is sent the remote
+/// PTY size and the compression offer, if it supports compression, and is
+/// asked to redraw the screen, as it may have missed them or lost the
+/// compression state. Runs once per handshake.
+async fn announce(client: AsyncClient, session: Session, channel: String) -> anyhow::Result<()> {
+    let Session { output, codec, handshake, wire, open } = session;
+    let size = output
+        .lock()
+        .map(|output| output.remote_pty_size())
+        .ok();
+    let (opens, compression) = handshake
+        .lock()
+        .map(|handshake| (handshake.opens(), handshake.supports(Capability::Compression)))
+        .unwrap_or((false, false));
+    if opens {
+        let size = size.unwrap_or(open.size);
+        let open = SessionOpen { size, ..open.as_ref().clone() };
+        let payload = wire.encode(Body::Open(open));
+        client.publish(format!("{}/open", channel), QoS::AtMostOnce, false, payload).await?;
+        return Ok(());
+    }
+    if let Some(size) = size {
+        resize::publish(&client, &wire, &format!("{}/resize", channel), size).await?;
+    }
+    if compression {
+        let offer = codec.lock().map(|codec| codec.offer()).unwrap_or_default();
+        control(&client, &wire, &channel, offer).await?;
+    }
+    if let Ok(mut output) = output.lock() {
+        let _ = output.refreshing();
+    }
+    control(&client, &wire, &channel, "refresh".to_string()).await
+}
+
+/// Publish a control command for the agent on `<channel>/ctl`.
+async fn control(client: &AsyncClient, wire: &Wire, channel: &str, command: String) -> anyhow::Result<()> {
+    let payload = wire.encode(Body::Control(command));
+    client.publish(format!("{}/ctl", channel), QoS::AtMostOnce, false, payload).await?;
+    Ok(())
+}
@@ -0,0 +1,357 @@
+use crate::output::Output;
+use crate::resize;
+use crate::streams::StreamMux;
+use crate::upload;
+use mqttshell_proto::envelope::{ Body, Wire };
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use tokio::time::{ sleep, Duration };
+
+/// Key that opens the escape menu.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscapeKey {
+    /// A printable character recognised only at the start of a line, like
+    /// ssh's `~`.
+    LineStart(char),
+    /// A control key recognised anywhere, like tmux's prefix.
+    Prefix(char),
+    None,
+}
+
+impl FromStr for EscapeKey {
+    type Err = String;
+
+    /// `~` (or any other printable character), `ctrl-]` / `^]`, or `none`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let control = s
+            .strip_prefix("ctrl-")
+            .or_else(|| s.strip_prefix("C-"))
+            .or_else(|| s.strip_prefix('^'));
+        let mut chars = control.unwrap_or(s).chars();
+        match (s, control, chars.next(), chars.next()) {
+            ("none", _, _, _) => Ok(EscapeKey::None),
+            (_, Some(_), Some(c), None) => {
+                control_code(c)
+                    .map(|code| EscapeKey::Prefix(code as char))
+                    .ok_or_else(|| format!("no control code for '{}'", c))
+            }
+            (_, None, Some(c), None) if !c.is_control() => Ok(EscapeKey::LineStart(c)),
+            _ => Err(format!("expected a character, ctrl-<key> or none, got '{}'", s)),
+        }
+    }
+}
+
+fn control_code(c: char) -> Option<u8> {
+    match c.to_ascii_uppercase() {
+        c @ ('@'..='_') => Some((c as u8) - b'@'),
+        _ => None,
+    }
+}
+
+impl EscapeKey {
+    /// How the key is written in the help text.
+    pub fn describe(&self) -> String {
+        match self {
+            EscapeKey::LineStart(c) => format!("{} (after Enter)", c),
+            EscapeKey::Prefix(c) => format!("Ctrl+{}", ((*c as u8) + b'@') as char),
+            EscapeKey::None => "none".to_string(),
+        }
+    }
+}
+
+/// Something the user asked the controller to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Hang up the remote shell and exit.
+    Disconnect,
+    /// Exit, leaving the remote shell running for the next controller.
+    Detach,
+    Status,
+    /// Send the window size again and ask the agent to redraw the screen.
+    Resize,
+    ToggleRecording,
+    /// Upload a local file into the agent's upload directory.
+    Upload(PathBuf),
+    Help,
+}
+
+/// What to do with an input unit (a key, or a byte in raw input mode).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Forward,
+    /// Send the escape character, then the unit. The menu was opened by
+    /// accident.
+    PrefixThenForward,
+    /// Send the escape character alone.
+    Prefix,
+    Swallow,
+    /// Show the prompt with the text typed so far.
+    Prompt(String),
+    Run(Command),
+}
+
+enum State {
+    Normal,
+    Open,
+    Upload(String),
+}
+
+/// The prompt shown while a path is typed for an upload.
+pub const UPLOAD_PROMPT: &str = "Upload file: ";
+
+pub struct EscapeMenu {
+    key: EscapeKey,
+    state: State,
+    at_line_start: bool,
+}
+
+/// Input unit fed to the menu: the character a key produces, or `None` for
+/// keys that produce no character (cursor keys, function keys, ...).
+pub type Unit = Option<char>;
+
+impl EscapeMenu {
+    pub fn new(key: EscapeKey) -> Self {
+        Self {
+            key,
+            state: State::Normal,
+            at_line_start: true,
+        }
+    }
+
+    fn key_char(&self) -> Option<char> {
+        match self.key {
+            EscapeKey::LineStart(c) | EscapeKey::Prefix(c) => Some(c),
+            EscapeKey::None => None,
+        }
+    }
+
+    /// Byte sequence of the escape character itself.
+    pub fn prefix_bytes(&self) -> Vec<u8> {
+        self.key_char()
+            .map(|c| c.to_string().into_bytes())
+            .unwrap_or_default()
+    }
+
+    pub fn feed(&mut self, unit: Unit) -> Action {
+        match std::mem::replace(&mut self.state, State::Normal) {
+            State::Normal => {
+                let opens = match self.key {
+                    EscapeKey::LineStart(c) => self.at_line_start && unit == Some(c),
+                    EscapeKey::Prefix(c) => unit == Some(c),
+                    EscapeKey::None => false,
+                };
+                self.at_line_start = matches!(unit, Some('\r' | '\n'));
+                if opens {
+                    self.state = State::Open;
+                    Action::Swallow
+                } else {
+                    Action::Forward
+                }
+            }
+            State::Open => {
+                // Like after Enter, the escape key works again right away
+                self.at_line_start = true;
+                let command = match unit {
+                    Some('.') => Command::Disconnect,
+                    Some('d') => Command::Detach,
+                    Some('s') => Command::Status,
+                    Some('r') => Command::Resize,
+                    Some('R') => Command::ToggleRecording,
+                    Some('?') => Command::Help,
+                    Some('u') => {
+                        self.state = State::Upload(String::new());
+                        return Action::Prompt(String::new());
+                    }
+                    Some(c) if Some(c) == self.key_char() => {
+                        self.at_line_start = false;
+                        return Action::Prefix;
+                    }
+                    _ => {
+                        self.at_line_start = matches!(unit, Some('\r' | '\n'));
+                        return Action::PrefixThenForward;
+                    }
+                };
+                Action::Run(command)
+            }
+            State::Upload(mut path) => {
+                self.at_line_start = true;
+                match unit {
+                    Some('\r' | '\n') if !path.is_empty() => {
+                        return Action::Run(Command::Upload(PathBuf::from(path)));
+                    }
+                    // Escape or Ctrl+C cancels
+                    Some('\x1b' | '\x03') => {
+                        return Action::Prompt("(cancelled)".to_string());
+                    }
+                    Some('\x7f' | '\x08') => {
+                        path.pop();
+                    }
+                    Some(c) if !c.is_control() => path.push(c),
+                    _ => {}
+                }
+                self.state = State::Upload(path.clone());
+                Action::Prompt(path)
+            }
+        }
+    }
+
+    /// Whether a path is being typed. The prompt is removed once this turns
+    /// false again.
+    pub fn prompting(&self) -> bool {
+        matches!(self.state, State::Upload(_))
+    }
+
+    pub fn help(&self) -> String {
+        let key = self.key.describe();
+        [
+            format!("Escape key: {}", key),
+            "  .  disconnect (hang up the remote shell)".to_string(),
+            "  d  detach (leave the remote shell running)".to_string(),
+            "  s  show status".to_string(),
+            "  r  resend the window size / redraw".to_string(),
+            "  R  start/stop recording (asciicast)".to_string(),
+            "  u  upload a file to the agent's upload directory".to_string(),
+            "  ?  this help".to_string(),
+            "  Press the escape key twice to send it".to_string(),
+        ].join("\r\n")
+    }
+}
+
+/// What the input loop should do after a unit went through [`Controls::input`].
+pub enum Flow {
+    /// Send these bytes to the remote shell (possibly none).
+    Send(Vec<u8>),
+    Exit,
+}
+
+/// Runs escape menu commands on behalf of the input loop.
+pub struct Controls {
+    menu: EscapeMenu,
+    client: AsyncClient,
+    wire: Arc<Wire>,
+    output: Arc<Mutex<Output>>,
+    mux: Arc<StreamMux>,
+    topic_ctl: String,
+    topic_resize: String,
+}
+
+impl Controls {
+    pub fn new(
+        key: EscapeKey,
+        client: AsyncClient,
+        wire: Arc<Wire>,
+        output: Arc<Mutex<Output>>,
+        mux: Arc<StreamMux>,
+        channel: &str
+    ) -> Self {
+        Self {
+            menu: EscapeMenu::new(key),
+            client,
+            wire,
+            output,
+            mux,
+            topic_ctl: format!("{}/ctl", channel),
+            topic_resize: format!("{}/resize", channel),
+        }
+    }
+
+    /// Pass one input unit through the escape menu. `bytes` is what the
+    /// unit is sent as when it is not meant for the menu.
+    pub async fn input(&mut self, unit: Unit, bytes: Vec<u8>) -> Flow {
+        let prompting = self.menu.prompting();
+        match self.menu.feed(unit) {
+            Action::Forward => Flow::Send(bytes),
+            Action::PrefixThenForward => Flow::Send([self.menu.prefix_bytes(), bytes].concat()),
+            Action::Prefix => Flow::Send(self.menu.prefix_bytes()),
+            Action::Swallow => Flow::Send(Vec::new()),
+            Action::Prompt(text) => {
+                let end = if self.menu.prompting() { "" } else { "\r\n" };
+                let lead = if prompting { "\r" } else { "\r\n" };
+                self.show(&format!("{}{}{}\x1b[K{}", lead, UPLOAD_PROMPT, text, end));
+                Flow::Send(Vec::new())
+            }
+            Action::Run(command) => self.run(command).await,
+        }
+    }
+
+    async fn control(&self, command: &str) -> anyhow::Result<()> {
+        let payload = self.wire.encode(Body::Control(command.to_string()));
+        self.client.publish(&self.topic_ctl, QoS::AtMostOnce, false, payload).await?;
+        Ok(())
+    }
+
+    fn show(&self, text: &str) {
+        show(&self.output, text);
+    }
+
+    async fn run(&mut self, command: Command) -> Flow {
+        match command {
+            Command::Disconnect => {
+                let _ = self.control("hangup").await;
+                // Give the event loop a moment to send it before exiting
+                sleep(Duration::from_millis(200)).await;
+                return Flow::Exit;
+            }
+            Command::Detach => {
+                return Flow::Exit;
+            }
+            Command::Status => {
+                let status = self.output
+                    .lock()
+                    .map(|output| output.status.render())
+                    .unwrap_or_default();
+                self.show(&format!("\r\n[{}]\r\n", status));
+            }
+            Command::Resize => {
+                let size = self.output
+                    .lock()
+                    .map(|output| output.remote_pty_size())
+                    .ok();
+                if let Some(size) = size {
+                    let _ = resize::publish(&self.client, &self.wire, &self.topic_resize, size).await;
+                }
+                if let Ok(mut output) = self.output.lock() {
+                    let _ = output.refreshing();
+                }
+                let _ = self.control("refresh").await;
+            }
+            Command::ToggleRecording => {
+                let result = self.output.lock().map(|mut output| output.toggle_recording());
+                let message = match result {
+                    Ok(Ok((path, true))) => format!("Recording to {}", path.display()),
+                    Ok(Ok((path, false))) => format!("Recording saved to {}", path.display()),
+                    Ok(Err(e)) => format!("Cannot record: {}", e),
+                    Err(_) => "Cannot record".to_string(),
+                };
+                self.show(&format!("\r\n[{}]\r\n", message));
+            }
+            Command::Upload(path) => {
+                // The session goes on while the file is sent
+                let mux = Arc::clone(&self.mux);
+                let output = Arc::clone(&self.output);
+                tokio::spawn(async move {
+                    let message = match upload::send(&mux, &path).await {
+                        Ok((name, size)) => format!("Uploaded {} ({} bytes)", name, size),
+                        Err(e) => format!("Upload failed: {}", e),
+                    };
+                    show(&output, &format!("\r\n[{}]\r\n", message));
+                });
+            }
+            Command::Help => {
+                self.show(&format!("\r\n{}\r\n", self.menu.help()));
+            }
+        }
+        Flow::Send(Vec::new())
+    }
+}
+
+/// Write `text` to the local terminal, past the remote screen.
+fn show(output: &Mutex<Output>, text: &str) {
+    if let Ok(mut output) = output.lock() {
+        let _ = output.write_local(text);
+        let _ = output.flush();
+    }
+}
@@ -0,0 +1,428 @@
+/// Which mouse events the remote application asked to receive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MouseTracking {
+    #[default]
+    Off,
+    /// Mode 9, button presses only.
+    X10,
+    /// Mode 1000, presses, releases and wheel.
+    Normal,
+    /// Mode 1002, also motion while a button is held.
+    ButtonEvent,
+    /// Mode 1003, also motion without buttons.
+    AnyEvent,
+}
+
+/// How mouse reports are encoded for the remote application.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MouseEncoding {
+    /// `CSI M` followed by three bytes.
+    #[default]
+    Default,
+    /// Mode 1005, like the default with UTF-8 coordinates.
+    Utf8,
+    /// Mode 1006, `CSI < b ; x ; y M/m`.
+    Sgr,
+    /// Mode 1015, `CSI b ; x ; y M`.
+    Urxvt,
+}
+
+/// Terminal modes the remote application switched on through its output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemoteModes {
+    /// DECCKM, cursor keys send `ESC O x` instead of `ESC [ x`.
+    pub application_cursor: bool,
+    /// DECKPAM, the keypad sends application sequences.
+    pub application_keypad: bool,
+    /// xterm modifyOtherKeys level, set with `CSI > 4 ; level m`.
+    pub modify_other_keys: u8,
+    /// Mode 2004, pastes are wrapped in `CSI 200 ~` / `CSI 201 ~`.
+    pub bracketed_paste: bool,
+    pub mouse_tracking: MouseTracking,
+    pub mouse_encoding: MouseEncoding,
+    /// Mode 1004, focus changes are reported as `CSI I` / `CSI O`.
+    pub focus_events: bool,
+    /// Active kitty keyboard protocol flags (top of the remote's stack).
+    pub kitty_keyboard: u8,
+}
+
+impl RemoteModes {
+    /// Modes the controller keeps on in the local terminal regardless of what
+    /// the remote output just set: bracketed paste, so pastes arrive as one
+    /// event, and SGR mouse reports while tracking is on, so crossterm can
+    /// decode every position before it is re-encoded for the remote. When the
+    /// local terminal speaks the kitty keyboard protocol its flags are
+    /// narrowed to `local_kitty_flags`, the ones crossterm can decode.
+    pub fn local_overrides(&self, local_kitty_flags: u8) -> String {
+        let mut sequence = String::from("\x1b[?2004h");
+        if self.mouse_tracking != MouseTracking::Off {
+            sequence.push_str("\x1b[?1006h");
+        }
+        if local_kitty_flags != 0 {
+            sequence.push_str(&format!("\x1b[={};1u", self.kitty_keyboard & local_kitty_flags));
+        }
+        sequence
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    Csi,
+    String,
+    StringEscape,
+}
+
+/// Incremental parser that follows mode changes in the remote output.
+/// Sequences split across MQTT messages are handled since the parser state
+/// is kept between calls to [`ModeTracker::feed`].
+#[derive(Debug, Default)]
+pub struct ModeTracker {
+    pub modes: RemoteModes,
+    state: State,
+    sequence: Vec<u8>,
+    local_sync_needed: bool,
+    kitty_stack: Vec<u8>,
+    /// Kitty flags reported back when the remote queries them.
+    kitty_supported: u8,
+    replies: Vec<u8>,
+}
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const MAX_SEQUENCE: usize = 64;
+const MAX_KITTY_STACK: usize = 16;
+
+/// Primary device attributes reported for the controller: a VT220 with
+/// ANSI colour, which is what xterm answers too.
+const DEVICE_ATTRIBUTES: &[u8] = b"\x1b[?62;22c";
+
+impl ModeTracker {
+    /// `kitty_supported` is the set of kitty keyboard flags the controller
+    /// can honour in its current setup.
+    pub fn new(kitty_supported: u8) -> Self {
+        Self {
+            kitty_supported,
+            ..Self::default()
+        }
+    }
+
+    /// Answers to queries found in the output since the last call, to be
+    /// sent back on the input topic.
+    ///
+    /// The local terminal sees the same queries, but crossterm swallows its
+    /// answers, so the controller has to reply on its behalf.
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.advance(byte);
+        }
+    }
+
+    fn advance(&mut self, byte: u8) {
+        self.state = match (self.state, byte) {
+            (State::String, BEL) => State::Ground,
+            (State::String, ESC) => State::StringEscape,
+            (State::String, _) => State::String,
+            (State::StringEscape, b'\\') => State::Ground,
+            (State::StringEscape, _) => State::String,
+            (_, ESC) => State::Escape,
+            (State::Ground, _) => State::Ground,
+            (State::Escape, b'[') => {
+                self.sequence.clear();
+                State::Csi
+            }
+            (State::Escape, b']' | b'P' | b'X' | b'^' | b'_') => State::String,
+            (State::Escape, 0x20..=0x2f) => State::EscapeIntermediate,
+            (State::Escape, _) => {
+                self.escape(byte);
+                State::Ground
+            }
+            (State::EscapeIntermediate, _) => State::Ground,
+            (State::Csi, 0x20..=0x3f) => {
+                if self.sequence.len() < MAX_SEQUENCE {
+                    self.sequence.push(byte);
+                }
+                State::Csi
+            }
+            (State::Csi, 0x40..=0x7e) => {
+                let sequence = std::mem::take(&mut self.sequence);
+                self.csi(&sequence, byte);
+                self.sequence = sequence;
+                State::Ground
+            }
+            (State::Csi, _) => State::Csi,
+        };
+    }
+
+    fn escape(&mut self, byte: u8) {
+        match byte {
+            b'=' => {
+                self.modes.application_keypad = true;
+            }
+            b'>' => {
+                self.modes.application_keypad = false;
+            }
+            b'c' => {
+                self.modes = RemoteModes::default();
+                self.kitty_stack.clear();
+                self.local_sync_needed = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, sequence: &[u8], action: u8) {
+        let (prefix, rest) = match sequence.first() {
+            Some(&p @ (b'<' | b'=' | b'>' | b'?')) => (Some(p), &sequence[1..]),
+            _ => (None, sequence),
+        };
+        let intermediates_at = rest
+            .iter()
+            .position(|b| (0x20..=0x2f).contains(b))
+            .unwrap_or(rest.len());
+        let (params, intermediates) = rest.split_at(intermediates_at);
+        let params: Vec<u16> = params
+            .split(|&b| b == b';')
+            .map(|p| {
+                std::str::from_utf8(p)
+                    .ok()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        match (prefix, intermediates, action) {
+            (Some(b'?'), [], b'h' | b'l') => {
+                let enable = action == b'h';
+                for &mode in &params {
+                    self.private_mode(mode, enable);
+                }
+            }
+            (Some(b'>'), [], b'm') if params.first() == Some(&4) => {
+                self.modes.modify_other_keys = params.get(1).copied().unwrap_or(0) as u8;
+            }
+            (None, [], b'c') if params.iter().all(|&p| p == 0) => {
+                self.replies.extend_from_slice(DEVICE_ATTRIBUTES);
+            }
+            (Some(b'?'), [], b'u') => {
+                let flags = self.modes.kitty_keyboard & self.kitty_supported;
+                self.replies.extend_from_slice(format!("\x1b[?{}u", flags).as_bytes());
+            }
+            (Some(b'>'), [], b'u') => {
+                if self.kitty_stack.len() == MAX_KITTY_STACK {
+                    self.kitty_stack.remove(0);
+                }
+                self.kitty_stack.push(self.modes.kitty_keyboard);
+                self.set_kitty_flags(params.first().copied().unwrap_or(0) as u8);
+            }
+            (Some(b'<'), [], b'u') => {
+                let count = params.first().copied().unwrap_or(1).max(1) as usize;
+                let mut flags = self.modes.kitty_keyboard;
+                for _ in 0..count {
+                    flags = self.kitty_stack.pop().unwrap_or(0);
+                }
+                self.set_kitty_flags(flags);
+            }
+            (Some(b'='), [], b'u') => {
+                let flags = params.first().copied().unwrap_or(0) as u8;
+                let current = self.modes.kitty_keyboard;
+                let flags = match params.get(1).copied().unwrap_or(1) {
+                    2 => current | flags,
+                    3 => current & !flags,
+                    _ => flags,
+                };
+                self.set_kitty_flags(flags);
+            }
+            (None, [b'!'], b'p') => {
+                // DECSTR soft reset
+                self.modes.application_cursor = false;
+                self.modes.application_keypad = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the output since the last call touched a mode covered by
+    /// [`RemoteModes::local_overrides`]. The output is written to the local
+    /// terminal too, so those modes must then be asserted again.
+    pub fn take_local_sync_needed(&mut self) -> bool {
+        std::mem::take(&mut self.local_sync_needed)
+    }
+
+    fn set_kitty_flags(&mut self, flags: u8) {
+        self.modes.kitty_keyboard = flags & self.kitty_supported;
+        self.local_sync_needed = true;
+    }
+
+    fn private_mode(&mut self, mode: u16, enable: bool) {
+        match mode {
+            1 => {
+                self.modes.application_cursor = enable;
+            }
+            9 | 1000 | 1002 | 1003 => {
+                self.modes.mouse_tracking = match (enable, mode) {
+                    (false, _) => MouseTracking::Off,
+                    (true, 9) => MouseTracking::X10,
+                    (true, 1000) => MouseTracking::Normal,
+                    (true, 1002) => MouseTracking::ButtonEvent,
+                    (true, _) => MouseTracking::AnyEvent,
+                };
+                self.local_sync_needed = true;
+            }
+            1005 | 1006 | 1015 => {
+                let encoding = match mode {
+                    1005 => MouseEncoding::Utf8,
+                    1006 => MouseEncoding::Sgr,
+                    _ => MouseEncoding::Urxvt,
+                };
+                if enable {
+                    self.modes.mouse_encoding = encoding;
+                } else if self.modes.mouse_encoding == encoding {
+                    self.modes.mouse_encoding = MouseEncoding::Default;
+                }
+                self.local_sync_needed = true;
+            }
+            1004 => {
+                self.modes.focus_events = enable;
+            }
+            2004 => {
+                self.modes.bracketed_paste = enable;
+                self.local_sync_needed = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(output: &[u8]) -> ModeTracker {
+        let mut tracker = ModeTracker::new(0b11111);
+        tracker.feed(output);
+        tracker
+    }
+
+    #[test]
+    fn cursor_and_keypad_modes() {
+        let modes = tracker(b"\x1b[?1h\x1b=").modes;
+        assert!(modes.application_cursor);
+        assert!(modes.application_keypad);
+        let modes = tracker(b"\x1b[?1h\x1b=\x1b[?1l\x1b>").modes;
+        assert!(!modes.application_cursor);
+        assert!(!modes.application_keypad);
+        // DECSTR resets both
+        let modes = tracker(b"\x1b[?1h\x1b=\x1b[!p").modes;
+        assert_eq!(modes, RemoteModes::default());
+    }
+
+    #[test]
+    fn several_modes_in_one_sequence() {
+        let modes = tracker(b"\x1b[?1;1004;2004h").modes;
+        assert!(modes.application_cursor);
+        assert!(modes.focus_events);
+        assert!(modes.bracketed_paste);
+    }
+
+    #[test]
+    fn mouse_tracking_and_encoding() {
+        for (mode, tracking) in [
+            ("9", MouseTracking::X10),
+            ("1000", MouseTracking::Normal),
+            ("1002", MouseTracking::ButtonEvent),
+            ("1003", MouseTracking::AnyEvent),
+        ] {
+            let mut modes = tracker(format!("\x1b[?{}h", mode).as_bytes());
+            assert_eq!(modes.modes.mouse_tracking, tracking);
+            assert!(modes.take_local_sync_needed());
+            assert!(!modes.take_local_sync_needed());
+            modes.feed(format!("\x1b[?{}l", mode).as_bytes());
+            assert_eq!(modes.modes.mouse_tracking, MouseTracking::Off);
+        }
+        let mut sgr = tracker(b"\x1b[?1000;1006h");
+        assert_eq!(sgr.modes.mouse_encoding, MouseEncoding::Sgr);
+        // Switching off another encoding leaves the active one alone
+        sgr.feed(b"\x1b[?1015l");
+        assert_eq!(sgr.modes.mouse_encoding, MouseEncoding::Sgr);
+        sgr.feed(b"\x1b[?1006l");
+        assert_eq!(sgr.modes.mouse_encoding, MouseEncoding::Default);
+        assert_eq!(tracker(b"\x1b[?1005h").modes.mouse_encoding, MouseEncoding::Utf8);
+    }
+
+    #[test]
+    fn sequences_split_across_messages() {
+        let mut tracker = ModeTracker::new(0);
+        for byte in b"text\x1b[?1000h\x1b[?1h" {
+            tracker.feed(&[*byte]);
+        }
+        assert_eq!(tracker.modes.mouse_tracking, MouseTracking::Normal);
+        assert!(tracker.modes.application_cursor);
+    }
+
+    #[test]
+    fn strings_are_skipped() {
+        // Neither the title nor the DCS payload is parsed as a mode change
+        let modes = tracker(b"\x1b]2;\x1b[?1h\x07\x1bP\x1b[?1000h\x1b\\").modes;
+        assert_eq!(modes, RemoteModes::default());
+        let modes = tracker(b"\x1b]0;title\x1b\\\x1b[?1h").modes;
+        assert!(modes.application_cursor);
+    }
+
+    #[test]
+    fn modify_other_keys_level() {
+        assert_eq!(tracker(b"\x1b[>4;2m").modes.modify_other_keys, 2);
+        assert_eq!(tracker(b"\x1b[>4;2m\x1b[>4m").modes.modify_other_keys, 0);
+        // Plain SGR is not mistaken for it
+        assert_eq!(tracker(b"\x1b[4;2m").modes.modify_other_keys, 0);
+    }
+
+    #[test]
+    fn device_attributes_are_answered() {
+        let mut queried = tracker(b"\x1b[c\x1b[0c\x1b[>c");
+        assert_eq!(queried.take_replies(), [DEVICE_ATTRIBUTES, DEVICE_ATTRIBUTES].concat());
+        assert!(queried.take_replies().is_empty());
+    }
+
+    #[test]
+    fn kitty_flags_stack() {
+        let mut tracker = ModeTracker::new(0b00011);
+        tracker.feed(b"\x1b[>1u\x1b[>31u");
+        // Flags the controller cannot honour are left out
+        assert_eq!(tracker.modes.kitty_keyboard, 0b00011);
+        tracker.feed(b"\x1b[?u");
+        assert_eq!(tracker.take_replies(), b"\x1b[?3u");
+        tracker.feed(b"\x1b[<u");
+        assert_eq!(tracker.modes.kitty_keyboard, 1);
+        tracker.feed(b"\x1b[=2;2u");
+        assert_eq!(tracker.modes.kitty_keyboard, 3);
+        tracker.feed(b"\x1b[=1;3u");
+        assert_eq!(tracker.modes.kitty_keyboard, 2);
+        tracker.feed(b"\x1b[<5u");
+        assert_eq!(tracker.modes.kitty_keyboard, 0);
+    }
+
+    #[test]
+    fn full_reset() {
+        let mut reset = tracker(b"\x1b[?1;1000;2004h\x1b=\x1b[>1u");
+        reset.take_local_sync_needed();
+        reset.feed(b"\x1bc");
+        assert_eq!(reset.modes, RemoteModes::default());
+        assert!(reset.take_local_sync_needed());
+    }
+
+    #[test]
+    fn local_overrides() {
+        let modes = tracker(b"\x1b[?1002h\x1b[>3u").modes;
+        assert_eq!(modes.local_overrides(0), "\x1b[?2004h\x1b[?1006h");
+        assert_eq!(modes.local_overrides(1), "\x1b[?2004h\x1b[?1006h\x1b[=1;1u");
+        assert_eq!(RemoteModes::default().local_overrides(0), "\x1b[?2004h");
+    }
+}
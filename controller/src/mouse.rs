@@ -0,0 +1,89 @@
+use crate::modes::{ MouseEncoding, MouseTracking, RemoteModes };
+use crossterm::event::{ KeyModifiers, MouseButton, MouseEvent, MouseEventKind };
+
+const MOTION: u8 = 32;
+const RELEASE: u8 = 3;
+
+fn button_code(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0,
+        MouseButton::Middle => 1,
+        MouseButton::Right => 2,
+    }
+}
+
+fn modifier_bits(mods: KeyModifiers) -> u8 {
+    let mut bits = 0;
+    if mods.contains(KeyModifiers::SHIFT) {
+        bits |= 4;
+    }
+    if mods.intersects(KeyModifiers::ALT | KeyModifiers::META) {
+        bits |= 8;
+    }
+    if mods.contains(KeyModifiers::CONTROL) {
+        bits |= 16;
+    }
+    bits
+}
+
+/// Encode a local mouse event for the remote application, following the
+/// tracking mode and encoding it requested. Returns `None` for events the
+/// remote did not ask for.
+pub fn encode(event: &MouseEvent, modes: &RemoteModes) -> Option<Vec<u8>> {
+    let tracking = modes.mouse_tracking;
+    if tracking == MouseTracking::Off {
+        return None;
+    }
+
+    let (code, release) = match event.kind {
+        MouseEventKind::Down(button) => (button_code(button), false),
+        MouseEventKind::Up(button) if tracking != MouseTracking::X10 => (button_code(button), true),
+        MouseEventKind::Drag(button)
+            if matches!(tracking, MouseTracking::ButtonEvent | MouseTracking::AnyEvent) => {
+            (button_code(button) + MOTION, false)
+        }
+        MouseEventKind::Moved if tracking == MouseTracking::AnyEvent => (RELEASE + MOTION, false),
+        MouseEventKind::ScrollUp if tracking != MouseTracking::X10 => (64, false),
+        MouseEventKind::ScrollDown if tracking != MouseTracking::X10 => (65, false),
+        MouseEventKind::ScrollLeft if tracking != MouseTracking::X10 => (66, false),
+        MouseEventKind::ScrollRight if tracking != MouseTracking::X10 => (67, false),
+        _ => {
+            return None;
+        }
+    };
+
+    let code = if tracking == MouseTracking::X10 {
+        code
+    } else {
+        code | modifier_bits(event.modifiers)
+    };
+    let x = event.column as u32 + 1;
+    let y = event.row as u32 + 1;
+
+    let sequence = match modes.mouse_encoding {
+        MouseEncoding::Sgr => {
+            let action = if release { 'm' } else { 'M' };
+            format!("\x1b[<{};{};{}{}", code, x, y, action).into_bytes()
+        }
+        MouseEncoding::Urxvt => {
+            let code = if release { (code & !3) | RELEASE } else { code };
+            format!("\x1b[{};{};{}M", code as u32 + 32, x, y).into_bytes()
+        }
+        MouseEncoding::Utf8 => {
+            let code = if release { (code & !3) | RELEASE } else { code };
+            let mut sequence = b"\x1b[M".to_vec();
+            for value in [code as u32 + 32, x.min(2015) + 32, y.min(2015) + 32] {
+                let mut utf8 = [0u8; 4];
+                let c = char::from_u32(value)?;
+                sequence.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            sequence
+        }
+        MouseEncoding::Default => {
+            let code = if release { (code & !3) | RELEASE } else { code };
+            // Positions beyond 223 cannot be represented in a single byte
+            vec![0x1b, b'[', b'M', code + 32, (x.min(223) + 32) as u8, (y.min(223) + 32) as u8]
+        }
+    };
+    Some(sequence)
+}
@@ -0,0 +1,406 @@
+use crate::predict::{ PredictMode, Predictor, Typed };
+use mqttshell_proto::messages::TerminalResize;
+use crate::status::{ self, Status, StatusLine, StatusMode };
+use encoding_rs::{ Decoder, Encoder, EncoderResult, Encoding, UTF_8 };
+use std::fs::File;
+use std::io::{ self, BufWriter, Stdout, Write };
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{ Instant, SystemTime, UNIX_EPOCH };
+
+/// Look up a `--remote-charset` label such as `latin1`, `cp1252` or
+/// `euc-jp`. UTF-8 needs no conversion and yields `None`.
+pub fn parse_charset(label: &str) -> anyhow::Result<Option<&'static Encoding>> {
+    let encoding = Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| anyhow::anyhow!("unknown character set '{}'", label))?;
+    Ok(if encoding == UTF_8 { None } else { Some(encoding) })
+}
+
+/// Writes remote output to the local terminal. Payload bytes are passed
+/// through unchanged, so UTF-8 characters split across messages and
+/// non-text data reach the terminal intact, unless a remote charset is set,
+/// in which case they are decoded to UTF-8 first. The decoder keeps
+/// partial characters between messages.
+///
+/// The controller's own state is presented here as well, in the window
+/// title or on a reserved status line depending on the [`StatusMode`], and
+/// so is the predicted echo of typed keys.
+pub struct Output {
+    writer: BufWriter<Stdout>,
+    decoder: Option<Decoder>,
+    pub status: Status,
+    mode: StatusMode,
+    line: Option<StatusLine>,
+    verbose: bool,
+    /// Size of the remote PTY.
+    size: TerminalResize,
+    recording: Option<Recording>,
+    predict: PredictMode,
+    predictor: Option<Predictor>,
+}
+
+/// Session recording in asciicast v2 format, playable with
+/// `asciinema play`.
+struct Recording {
+    path: PathBuf,
+    file: BufWriter<File>,
+    started: Instant,
+    utf8: Decoder,
+}
+
+impl Recording {
+    fn create(channel: &str, size: TerminalResize) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let path = PathBuf::from(format!("mqttshell-{}-{}.cast", channel.replace('/', "_"), timestamp));
+        let mut file = BufWriter::new(File::create(&path)?);
+        let header = serde_json::json!({
+            "version": 2,
+            "width": size.cols,
+            "height": size.rows,
+            "timestamp": timestamp,
+        });
+        writeln!(file, "{}", header)?;
+        Ok(Self {
+            path,
+            file,
+            started: Instant::now(),
+            utf8: UTF_8.new_decoder_without_bom_handling(),
+        })
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> io::Result<()> {
+        let time = self.started.elapsed().as_secs_f64();
+        writeln!(self.file, "{}", serde_json::json!([time, kind, data]))
+    }
+
+    fn output(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let capacity = self.utf8.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3);
+        let mut text = String::with_capacity(capacity);
+        let _ = self.utf8.decode_to_string(bytes, &mut text, false);
+        self.event("o", &text)
+    }
+}
+
+impl Output {
+    pub fn new(
+        charset: Option<&'static Encoding>,
+        mode: StatusMode,
+        status: Status,
+        predict: PredictMode,
+        verbose: bool
+    ) -> Self {
+        Self {
+            writer: BufWriter::with_capacity(64 * 1024, io::stdout()),
+            decoder: charset.map(|encoding| encoding.new_decoder_without_bom_handling()),
+            status,
+            mode,
+            line: None,
+            verbose,
+            size: TerminalResize { rows: 24, cols: 80, pixel_width: 0, pixel_height: 0 },
+            recording: None,
+            predict,
+            predictor: None,
+        }
+    }
+
+    /// Set up the status presentation for a local terminal of `local`
+    /// size. Returns the size the remote PTY should have.
+    pub fn start(&mut self, local: TerminalResize) -> io::Result<TerminalResize> {
+        match self.mode {
+            StatusMode::Off => {}
+            StatusMode::Title => {
+                // Save the local title so it can be put back on exit
+                self.writer.write_all(b"\x1b[22;2t")?;
+            }
+            StatusMode::Line => {
+                let mut line = StatusLine::new(local.rows, local.cols);
+                self.writer.write_all(&line.start())?;
+                self.line = Some(line);
+            }
+        }
+        self.status_changed()?;
+        self.size = self.remote_size(local);
+        if self.predict != PredictMode::Off {
+            // The remote output continues from wherever the cursor is
+            let cursor = match self.line {
+                Some(_) => (0, 0),
+                None => {
+                    self.writer.flush()?;
+                    crossterm::cursor::position().map_or((0, 0), |(col, row)| (row, col))
+                }
+            };
+            self.predictor = Some(Predictor::new(self.predict, self.size.rows, self.size.cols, cursor));
+        }
+        Ok(self.size)
+    }
+
+    /// Undo [`Output::start`].
+    pub fn stop(&mut self) -> io::Result<()> {
+        if let Some(mut recording) = self.recording.take() {
+            recording.file.flush()?;
+        }
+        if self.mode == StatusMode::Off && !self.status.connected {
+            self.writer.write_all(b"\x1b[23;2t")?;
+        }
+        match self.mode {
+            StatusMode::Off => {}
+            StatusMode::Title => self.writer.write_all(b"\x1b[23;2t")?,
+            StatusMode::Line => {
+                if let Some(line) = self.line.take() {
+                    self.writer.write_all(&line.stop())?;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Follow a local window size change. Returns the size the remote PTY
+    /// should have.
+    pub fn resize(&mut self, local: TerminalResize) -> io::Result<TerminalResize> {
+        if let Some(line) = &mut self.line {
+            let bytes = line.resize(local.rows, local.cols);
+            self.writer.write_all(&bytes)?;
+            self.status_changed()?;
+        }
+        self.size = self.remote_size(local);
+        if let Some(predictor) = &mut self.predictor {
+            predictor.resize(self.size.rows, self.size.cols);
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.event("r", &format!("{}x{}", self.size.cols, self.size.rows))?;
+        }
+        Ok(self.size)
+    }
+
+    /// Size of the remote PTY.
+    pub fn remote_pty_size(&self) -> TerminalResize {
+        self.size
+    }
+
+    /// Start recording the session into a new file in the current
+    /// directory, or stop the running recording. Returns the file name and
+    /// whether recording is now on.
+    pub fn toggle_recording(&mut self) -> io::Result<(PathBuf, bool)> {
+        if let Some(mut recording) = self.recording.take() {
+            recording.file.flush()?;
+            return Ok((recording.path, false));
+        }
+        let recording = Recording::create(&self.status.channel, self.size)?;
+        let path = recording.path.clone();
+        self.recording = Some(recording);
+        Ok((path, true))
+    }
+
+    fn remote_size(&self, local: TerminalResize) -> TerminalResize {
+        match &self.line {
+            Some(line) => {
+                let rows = line.remote_rows();
+                TerminalResize {
+                    rows,
+                    pixel_height: ((local.pixel_height as u32) * (rows as u32) /
+                        (local.rows.max(1) as u32)) as u16,
+                    ..local
+                }
+            }
+            None => local,
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let decoded;
+        let bytes = match &mut self.decoder {
+            Some(decoder) => {
+                let capacity = decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3);
+                let mut text = String::with_capacity(capacity);
+                let _ = decoder.decode_to_string(bytes, &mut text, false);
+                decoded = text.into_bytes();
+                &decoded[..]
+            }
+            None => bytes,
+        };
+        if let Some(recording) = &mut self.recording {
+            recording.output(bytes)?;
+        }
+        if let Some(predictor) = &mut self.predictor {
+            self.writer.write_all(&predictor.before_output())?;
+        }
+        match &mut self.line {
+            Some(line) => {
+                let filtered = line.filter(bytes);
+                self.writer.write_all(&filtered)?;
+                if line.take_damaged() {
+                    let bytes = line.draw(&self.status.render());
+                    self.writer.write_all(&bytes)?;
+                }
+            }
+            None => self.writer.write_all(bytes)?,
+        }
+        if let Some(predictor) = &mut self.predictor {
+            self.writer.write_all(&predictor.after_output(bytes))?;
+        }
+        Ok(())
+    }
+
+    /// Show the predicted effect of a key just sent to the remote.
+    pub fn predict(&mut self, typed: Typed) -> io::Result<()> {
+        if let Some(predictor) = &mut self.predictor {
+            self.writer.write_all(&predictor.key(typed))?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Call when asking the agent for a screen refresh; predictions wait
+    /// for the redraw, so they are checked against what is really there.
+    pub fn refreshing(&mut self) -> io::Result<()> {
+        if let Some(predictor) = &mut self.predictor {
+            self.writer.write_all(&predictor.reseed())?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Follow the echo mode reported by the agent; predictions stop while
+    /// the remote does not echo.
+    pub fn set_echo(&mut self, echo: bool) -> io::Result<()> {
+        if let Some(predictor) = &mut self.predictor {
+            self.writer.write_all(&predictor.set_echo(echo))?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Take back predictions the remote did not confirm in time.
+    pub fn expire_predictions(&mut self) -> io::Result<()> {
+        if let Some(predictor) = &mut self.predictor {
+            let bytes = predictor.tick();
+            if !bytes.is_empty() {
+                self.writer.write_all(&bytes)?;
+                self.writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write controller-generated sequences, which are always ASCII.
+    pub fn write_local(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(text.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Show [`Output::status`] again after it was modified.
+    pub fn status_changed(&mut self) -> io::Result<()> {
+        match (&self.mode, &self.line) {
+            (StatusMode::Title, _) => {
+                write!(self.writer, "\x1b]2;{}\x07", self.status.render())?;
+            }
+            (StatusMode::Line, Some(line)) => {
+                let bytes = line.draw(&self.status.render());
+                self.writer.write_all(&bytes)?;
+                if let Some(predictor) = &self.predictor {
+                    self.writer.write_all(&predictor.reposition())?;
+                }
+            }
+            _ => {}
+        }
+        self.writer.flush()
+    }
+
+    /// Follow the broker connection going down or coming back. Without a
+    /// status display the outage is flagged in the window title, which is
+    /// put back once reconnected.
+    pub fn set_connected(&mut self, connected: bool) -> io::Result<()> {
+        if self.status.connected == connected {
+            return Ok(());
+        }
+        self.status.connected = connected;
+        if !connected {
+            self.status.latency = None;
+        }
+        if self.mode == StatusMode::Off {
+            if connected {
+                self.writer.write_all(b"\x1b[23;2t")?;
+            } else {
+                write!(self.writer, "\x1b[22;2t\x1b]2;{}\x07", self.status.render())?;
+            }
+        }
+        self.status_changed()
+    }
+
+    /// Report a diagnostic message. It becomes part of the status, or with
+    /// no status presentation is only printed in verbose mode, so nothing
+    /// is injected into the remote application's screen by default.
+    pub fn notify(&mut self, message: String) -> io::Result<()> {
+        if self.mode == StatusMode::Off {
+            if self.verbose {
+                write!(self.writer, "{}\r\n", status::printable(&message))?;
+                self.writer.flush()?;
+            }
+            return Ok(());
+        }
+        self.status.notice = Some(message);
+        self.status_changed()
+    }
+}
+
+/// [`Output::notify`] for tasks sharing the output, which skips the
+/// message if the lock is poisoned.
+pub(crate) fn notify(output: &Mutex<Output>, message: String) {
+    if let Ok(mut output) = output.lock() {
+        let _ = output.notify(message);
+    }
+}
+
+/// Converts UTF-8 input from the local terminal to the remote charset.
+/// Characters the charset cannot represent are sent as `?`.
+pub struct InputEncoder {
+    utf8: Decoder,
+    encoder: Encoder,
+}
+
+impl InputEncoder {
+    pub fn new(charset: &'static Encoding) -> Self {
+        Self {
+            utf8: UTF_8.new_decoder_without_bom_handling(),
+            encoder: charset.new_encoder(),
+        }
+    }
+
+    pub fn encode(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let capacity = self.utf8.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3);
+        let mut text = String::with_capacity(capacity);
+        let _ = self.utf8.decode_to_string(bytes, &mut text, false);
+
+        let mut encoded = Vec::with_capacity(text.len());
+        let mut rest = text.as_str();
+        loop {
+            let (result, read) = self.encoder.encode_from_utf8_to_vec_without_replacement(
+                rest,
+                &mut encoded,
+                false
+            );
+            rest = &rest[read..];
+            match result {
+                EncoderResult::InputEmpty => {
+                    break;
+                }
+                EncoderResult::OutputFull => {
+                    let needed = self.encoder
+                        .max_buffer_length_from_utf8_without_replacement(rest.len())
+                        .unwrap_or(rest.len() * 4);
+                    encoded.reserve(needed);
+                }
+                EncoderResult::Unmappable(_) => {
+                    encoded.push(b'?');
+                }
+            }
+        }
+        encoded
+    }
+}
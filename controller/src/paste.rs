@@ -0,0 +1,62 @@
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// Largest input message published for a paste; bigger pastes are split.
+pub const MAX_PASTE_MESSAGE: usize = 4096;
+
+/// Encode pasted text the way a terminal would send it: newlines become
+/// carriage returns and, when the remote application enabled bracketed
+/// paste, the text is wrapped in paste markers. Control characters other
+/// than tab and carriage return are dropped, as xterm does, so the text
+/// can neither end the paste early nor send escape sequences of its own.
+pub fn encode(text: &str, bracketed: bool) -> Vec<u8> {
+    let text: String = text
+        .replace("\r\n", "\r")
+        .replace('\n', "\r")
+        .chars()
+        .filter(|&c| !c.is_control() || c == '\t' || c == '\r')
+        .collect();
+    let mut bytes = Vec::with_capacity(text.len() + PASTE_START.len() + PASTE_END.len());
+    if bracketed {
+        bytes.extend_from_slice(PASTE_START);
+    }
+    bytes.extend_from_slice(text.as_bytes());
+    if bracketed {
+        bytes.extend_from_slice(PASTE_END);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newlines_become_carriage_returns() {
+        assert_eq!(encode("one\ntwo\r\nthree\r", false), b"one\rtwo\rthree\r");
+        assert_eq!(encode("a\tb", false), b"a\tb");
+    }
+
+    #[test]
+    fn bracketed_paste_is_wrapped() {
+        assert_eq!(encode("ls\n", true), b"\x1b[200~ls\r\x1b[201~");
+        assert_eq!(encode("", true), b"\x1b[200~\x1b[201~");
+    }
+
+    #[test]
+    fn end_marker_cannot_be_pasted() {
+        assert_eq!(encode("x\x1b[201~echo pwned\r", true), b"\x1b[200~x[201~echo pwned\r\x1b[201~");
+        // Removing one marker must not join the rest into another
+        assert_eq!(
+            encode("\x1b[20\x1b[201~1~echo pwned\r", true),
+            b"\x1b[200~[20[201~1~echo pwned\r\x1b[201~"
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped() {
+        assert_eq!(encode("a\x03b\x1b[2Jc\x7fd", false), b"ab[2Jcd");
+        assert_eq!(encode("a\u{9b}201~b\u{85}c", true), "\x1b[200~a201~bc\x1b[201~".as_bytes());
+        assert_eq!(encode("héllo ✓", true), "\x1b[200~héllo ✓\x1b[201~".as_bytes());
+    }
+}
@@ -0,0 +1,365 @@
+use crossterm::event::{ KeyCode, KeyEvent, KeyModifiers };
+use std::time::{ Duration, Instant };
+use unicode_width::UnicodeWidthChar;
+
+/// When typed characters are echoed locally before the remote confirms them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PredictMode {
+    #[default]
+    Off,
+    /// Only while the echo round trip is slow.
+    Adaptive,
+    Always,
+}
+
+/// Echo round trip above which adaptive mode shows predictions.
+const ADAPTIVE_THRESHOLD: Duration = Duration::from_millis(30);
+/// How long a prediction may stay unconfirmed, on top of twice the echo
+/// round trip, before it is considered wrong.
+const GRACE: Duration = Duration::from_millis(250);
+/// Time allowed for a confirmation before the round trip was measured.
+const UNMEASURED_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a key press does, as far as prediction is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Typed {
+    Char(char),
+    Backspace,
+    Left,
+    Right,
+    /// Anything whose effect cannot be guessed, like Enter or a control key.
+    Other,
+}
+
+impl Typed {
+    pub fn from_key(key: &KeyEvent) -> Self {
+        if key.modifiers.intersects(!KeyModifiers::SHIFT) {
+            return Typed::Other;
+        }
+        match key.code {
+            KeyCode::Char(c) if c.width() == Some(1) => Typed::Char(c),
+            KeyCode::Backspace => Typed::Backspace,
+            KeyCode::Left => Typed::Left,
+            KeyCode::Right => Typed::Right,
+            _ => Typed::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// The cell will show this character.
+    Cell(char),
+    /// The cursor will be here.
+    Cursor,
+}
+
+/// How far the shadow screen can be trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Seed {
+    /// It only has the output seen since it was created or last seeded.
+    Blank,
+    /// The remote was asked to redraw, the next output seeds it.
+    Requested,
+    Seeded,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Prediction {
+    row: u16,
+    col: u16,
+    kind: Kind,
+    typed: Instant,
+}
+
+/// Guesses the echo of typed characters and cursor movement on the
+/// cursor's line, like mosh does. Predictions are drawn underlined over
+/// the remote screen, and taken back before remote output is written, so
+/// the output lands on the screen the remote application expects. A shadow
+/// of the remote screen tells which predictions the output confirmed.
+///
+/// Nothing is predicted before the remote redrew the screen on request, as
+/// the shadow does not know what was on it before. Nothing is shown until a
+/// prediction has been confirmed, nor after one turned out wrong until the
+/// next confirmation, nor while the agent reports that the remote does not
+/// echo.
+pub struct Predictor {
+    mode: PredictMode,
+    screen: vt100::Parser,
+    seed: Seed,
+    predictions: Vec<Prediction>,
+    /// Where the cursor will be once the predictions are confirmed.
+    cursor: (u16, u16),
+    /// Cells currently overdrawn with a prediction.
+    drawn: Vec<(u16, u16)>,
+    /// Whether the local cursor was moved away from the remote cursor.
+    moved: bool,
+    echo: bool,
+    confident: bool,
+    /// Set by keys that cannot be predicted, until the output catches up.
+    suspended: bool,
+    /// Smoothed round trip from key press to echo.
+    srtt: Option<Duration>,
+}
+
+impl Predictor {
+    /// `rows` and `cols` are the size of the remote PTY, `cursor` the
+    /// (row, column) the local cursor starts at.
+    pub fn new(mode: PredictMode, rows: u16, cols: u16, cursor: (u16, u16)) -> Self {
+        let mut screen = vt100::Parser::new(rows, cols, 0);
+        screen.process(format!("\x1b[{};{}H", cursor.0 + 1, cursor.1 + 1).as_bytes());
+        Self {
+            mode,
+            screen,
+            seed: Seed::Blank,
+            predictions: Vec::new(),
+            cursor: (0, 0),
+            drawn: Vec::new(),
+            moved: false,
+            echo: true,
+            confident: false,
+            suspended: false,
+            srtt: None,
+        }
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        self.screen.screen_mut().set_size(rows, cols);
+        // The remote redraws after a resize, which covers the predictions
+        self.predictions.clear();
+        self.drawn.clear();
+        self.moved = false;
+    }
+
+    /// Call when asking the remote to redraw the screen: predictions stop
+    /// until the redraw arrives. Returns what to write to take them back.
+    pub fn reseed(&mut self) -> Vec<u8> {
+        self.seed = Seed::Requested;
+        self.predictions.clear();
+        self.take_back()
+    }
+
+    /// Follow the echo mode reported by the agent. Returns what to write to
+    /// take the predictions back when echo goes off.
+    pub fn set_echo(&mut self, echo: bool) -> Vec<u8> {
+        self.echo = echo;
+        if echo {
+            return Vec::new();
+        }
+        self.predictions.clear();
+        self.take_back()
+    }
+
+    /// Predict the effect of a key just sent to the remote. Returns what to
+    /// write to show the prediction.
+    pub fn key(&mut self, typed: Typed) -> Vec<u8> {
+        if self.mode == PredictMode::Off || self.seed != Seed::Seeded || !self.echo || self.suspended {
+            return Vec::new();
+        }
+        let screen = self.screen.screen();
+        if screen.hide_cursor() {
+            return Vec::new();
+        }
+        let (_, cols) = screen.size();
+        let (row, col) = if self.predictions.is_empty() {
+            screen.cursor_position()
+        } else {
+            self.cursor
+        };
+        let last = self.predictions.last().map(|p| (p.row, p.col, p.kind));
+        let prediction = |col, kind| Prediction { row, col, kind, typed: Instant::now() };
+        match typed {
+            Typed::Char(c) if col + 1 < cols => {
+                self.predictions.push(prediction(col, Kind::Cell(c)));
+                self.cursor = (row, col + 1);
+            }
+            // Only a character predicted just before can be taken back
+            Typed::Backspace if matches!(last, Some((r, c, Kind::Cell(_))) if r == row && c + 1 == col) => {
+                self.predictions.pop();
+                self.cursor = (row, col - 1);
+            }
+            Typed::Left if col > 0 => {
+                self.predictions.push(prediction(col - 1, Kind::Cursor));
+                self.cursor = (row, col - 1);
+            }
+            Typed::Right if col + 1 < cols => {
+                self.predictions.push(prediction(col + 1, Kind::Cursor));
+                self.cursor = (row, col + 1);
+            }
+            _ => {
+                self.suspended = true;
+            }
+        }
+        self.render()
+    }
+
+    /// Call before writing remote output: takes the predictions off the
+    /// screen and puts the cursor back where the remote left it.
+    pub fn before_output(&mut self) -> Vec<u8> {
+        self.take_back()
+    }
+
+    /// Call after writing remote output: checks the predictions against it
+    /// and draws those still pending.
+    pub fn after_output(&mut self, bytes: &[u8]) -> Vec<u8> {
+        self.screen.process(bytes);
+        if self.seed == Seed::Requested {
+            self.seed = Seed::Seeded;
+        }
+        self.reconcile();
+        if self.predictions.is_empty() {
+            self.suspended = false;
+        }
+        self.render()
+    }
+
+    /// Give up on predictions the remote never confirmed. Returns what to
+    /// write to take them back.
+    pub fn tick(&mut self) -> Vec<u8> {
+        if self.expired() {
+            self.fail();
+            return self.take_back();
+        }
+        Vec::new()
+    }
+
+    /// Put the cursor back on the prediction after something else moved it
+    /// to the remote cursor.
+    pub fn reposition(&self) -> Vec<u8> {
+        if self.moved {
+            format!("\x1b[{};{}H", self.cursor.0 + 1, self.cursor.1 + 1).into_bytes()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.srtt.map_or(UNMEASURED_TIMEOUT, |srtt| srtt * 2 + GRACE)
+    }
+
+    fn expired(&self) -> bool {
+        let timeout = self.timeout();
+        self.predictions.first().is_some_and(|p| p.typed.elapsed() > timeout)
+    }
+
+    fn fail(&mut self) {
+        self.predictions.clear();
+        self.confident = false;
+    }
+
+    fn reconcile(&mut self) {
+        let screen = self.screen.screen();
+        let cursor = screen.cursor_position();
+        let confirmed = self.predictions.iter().rposition(|p| {
+            match p.kind {
+                Kind::Cell(c) => {
+                    screen
+                        .cell(p.row, p.col)
+                        .is_some_and(|cell| cell.contents().starts_with(c))
+                }
+                Kind::Cursor => cursor == (p.row, p.col),
+            }
+        });
+        if let Some(index) = confirmed {
+            let sample = self.predictions[index].typed.elapsed();
+            self.srtt = Some(match self.srtt {
+                Some(srtt) => (srtt * 7 + sample) / 8,
+                None => sample,
+            });
+            self.confident = true;
+            self.predictions.drain(..=index);
+        }
+        // The remote cursor went past a cell without the predicted character
+        let contradicted = self.predictions.first().is_some_and(|p| {
+            matches!(p.kind, Kind::Cell(_)) && cursor.0 == p.row && cursor.1 > p.col
+        });
+        if contradicted || self.expired() {
+            self.fail();
+        }
+    }
+
+    fn showing(&self) -> bool {
+        let fast = self.srtt.is_some_and(|srtt| srtt < ADAPTIVE_THRESHOLD);
+        self.confident && match self.mode {
+            PredictMode::Off => false,
+            PredictMode::Adaptive => !fast,
+            PredictMode::Always => true,
+        }
+    }
+
+    /// Redraw the predictions from scratch.
+    fn render(&mut self) -> Vec<u8> {
+        let mut bytes = self.take_back();
+        if self.predictions.is_empty() || !self.showing() {
+            return bytes;
+        }
+        for prediction in &self.predictions {
+            if let Kind::Cell(c) = prediction.kind {
+                bytes.extend(
+                    format!("\x1b[{};{}H\x1b[0;4m{}", prediction.row + 1, prediction.col + 1, c).bytes()
+                );
+                self.drawn.push((prediction.row, prediction.col));
+            }
+        }
+        bytes.extend(self.screen.screen().attributes_formatted());
+        self.moved = true;
+        bytes.extend(self.reposition());
+        bytes
+    }
+
+    /// Draw the remote contents of the overdrawn cells again and restore
+    /// the remote cursor and attributes.
+    fn take_back(&mut self) -> Vec<u8> {
+        if self.drawn.is_empty() && !self.moved {
+            return Vec::new();
+        }
+        let screen = self.screen.screen();
+        let mut bytes = Vec::new();
+        for (row, col) in self.drawn.drain(..) {
+            if let Some(cell) = screen.cell(row, col) {
+                bytes.extend(format!("\x1b[{};{}H", row + 1, col + 1).bytes());
+                bytes.extend(cell_formatted(cell));
+            }
+        }
+        bytes.extend(screen.cursor_state_formatted());
+        bytes.extend(screen.attributes_formatted());
+        self.moved = false;
+        bytes
+    }
+}
+
+/// A cell with its attributes, written at the cursor.
+fn cell_formatted(cell: &vt100::Cell) -> Vec<u8> {
+    let mut sgr = vec!["0".to_string()];
+    for (on, code) in [
+        (cell.bold(), "1"),
+        (cell.dim(), "2"),
+        (cell.italic(), "3"),
+        (cell.underline(), "4"),
+        (cell.inverse(), "7"),
+    ] {
+        if on {
+            sgr.push(code.to_string());
+        }
+    }
+    sgr.extend(color_formatted(cell.fgcolor(), 30));
+    sgr.extend(color_formatted(cell.bgcolor(), 40));
+    let contents = match cell.contents() {
+        "" if cell.is_wide_continuation() => "",
+        "" => " ",
+        contents => contents,
+    };
+    format!("\x1b[{}m{}", sgr.join(";"), contents).into_bytes()
+}
+
+/// SGR parameters selecting `color`, `base` being 30 for the foreground
+/// and 40 for the background.
+fn color_formatted(color: vt100::Color, base: u8) -> Option<String> {
+    match color {
+        vt100::Color::Default => None,
+        vt100::Color::Idx(i @ 0..=7) => Some((base + i).to_string()),
+        vt100::Color::Idx(i @ 8..=15) => Some((base + 60 + i - 8).to_string()),
+        vt100::Color::Idx(i) => Some(format!("{};5;{}", base + 8, i)),
+        vt100::Color::Rgb(r, g, b) => Some(format!("{};2;{};{};{}", base + 8, r, g, b)),
+    }
+}
@@ -0,0 +1,82 @@
+use crate::streams::StreamMux;
+use mqttshell_proto::envelope::{ Format, Wire };
+use mqttshell_proto::messages::StreamTarget;
+use rumqttc::v5::mqttbytes::v5::Packet;
+use rumqttc::v5::{ AsyncClient, Event, MqttOptions };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+use tokio::time::{ sleep, Duration };
+
+/// Parse a proxy target, `host:port`, `[v6addr]:port` or an absolute Unix socket path.
+pub fn parse_target(spec: &str) -> anyhow::Result<StreamTarget> {
+    if spec.starts_with('/') {
+        return Ok(StreamTarget::Unix { path: spec.to_string() });
+    }
+    let (host, port) = spec
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("expected HOST:PORT, got '{}'", spec))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = port.parse::<u16>().map_err(|_| anyhow::anyhow!("invalid port in '{}'", spec))?;
+    Ok(StreamTarget::Tcp { host, port })
+}
+
+/// Bridge stdin/stdout to a stream dialled by the agent, for use as an ssh
+/// `ProxyCommand`. Stdout carries the stream, so diagnostics go to stderr.
+pub async fn run(channel: &str, spec: &str, broker: (&str, u16), format: Format) -> anyhow::Result<()> {
+    let (host, port) = broker;
+    let target = parse_target(spec)?;
+
+    let client_id = format!("controller-proxy-{}", std::process::id());
+    let mut mqttoptions = MqttOptions::new(client_id, host, port);
+    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+    let mux = StreamMux::new(client, channel, Arc::new(Wire::new(format)));
+    mux.subscribe().await?;
+
+    let mux_dispatch = Arc::clone(&mux);
+    // Data the agent publishes during an outage is lost with the clean
+    // session, so a stream that lived through one counts as failed even
+    // when it ends normally
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let outage = Arc::clone(&interrupted);
+    tokio::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    mux_dispatch.dispatch(&String::from_utf8_lossy(&p.topic), &p.payload);
+                }
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    // A clean session lost the stream's subscriptions
+                    let mux = Arc::clone(&mux_dispatch);
+                    tokio::spawn(async move { mux.resubscribe().await });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    outage.store(true, Ordering::Relaxed);
+                    eprintln!("MQTT Error: {:?}", e);
+                    sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+
+    let stream = mux
+        .open(target).await
+        .map_err(|e| anyhow::anyhow!("agent on '{}' could not open {}: {}", channel, spec, e))?;
+    interrupted.store(false, Ordering::Relaxed);
+    let result = mux.pump(stream, tokio::io::stdin(), tokio::io::stdout()).await.and_then(|()| {
+        if interrupted.load(Ordering::Relaxed) {
+            anyhow::bail!("the broker connection dropped, data may have been lost");
+        }
+        Ok(())
+    });
+
+    // tokio's stdin keeps a blocking read in flight that would hold up
+    // runtime shutdown until the next byte arrives, so leave right away.
+    if let Err(e) = result {
+        eprintln!("Stream to {} broke: {:#}", spec, e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
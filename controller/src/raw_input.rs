@@ -0,0 +1,66 @@
+use crate::input::InputGate;
+use crate::menu::{ Controls, Flow };
+use std::io::Read;
+use tokio::sync::mpsc;
+
+/// Forward bytes read from the local TTY to the remote shell unchanged,
+/// except for escape menu input. Returns when the menu asks to leave,
+/// stdin is closed or the session ends for the reason sent on `rx_exit`.
+/// Nothing is read while `gate` is closed.
+pub async fn run(
+    mut controls: Controls,
+    tx_input: mpsc::UnboundedSender<Vec<u8>>,
+    rx_exit: &mut mpsc::UnboundedReceiver<String>,
+    mut gate: InputGate
+) {
+    let (tx_raw, mut rx_raw) = mpsc::unbounded_channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut buf = [0u8; 4096];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    break;
+                }
+                Ok(n) => {
+                    if tx_raw.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            reason = rx_exit.recv() => {
+                println!("\r\n{}", reason.unwrap_or_default());
+                break;
+            }
+            _ = gate.changed() => {}
+            bytes = rx_raw.recv(), if gate.is_open() => {
+                let Some(bytes) = bytes else {
+                    break;
+                };
+                let mut input = Vec::with_capacity(bytes.len());
+                let mut exit = false;
+                for &byte in &bytes {
+                    let unit = byte.is_ascii().then_some(byte as char);
+                    match controls.input(unit, vec![byte]).await {
+                        Flow::Send(bytes) => input.extend(bytes),
+                        Flow::Exit => {
+                            exit = true;
+                            break;
+                        }
+                    }
+                }
+                if !input.is_empty() {
+                    let _ = tx_input.send(input);
+                }
+                if exit {
+                    break;
+                }
+            }
+        }
+    }
+}
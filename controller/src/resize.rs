@@ -0,0 +1,66 @@
+use crate::output::Output;
+use mqttshell_proto::envelope::{ Body, Wire };
+use mqttshell_proto::messages::TerminalResize;
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
+use std::sync::{ Arc, Mutex };
+use tokio::signal::unix::{ signal, SignalKind };
+use tokio::time::{ timeout, Duration };
+
+/// Quiet period after the last SIGWINCH before the new size is sent, so a
+/// window drag produces one resize per pause instead of one per signal.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Size of the local terminal, falling back to 80x24.
+pub fn current() -> TerminalResize {
+    if let Ok(size) = crossterm::terminal::window_size() {
+        return TerminalResize {
+            rows: size.rows,
+            cols: size.columns,
+            pixel_width: size.width,
+            pixel_height: size.height,
+        };
+    }
+    let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+    TerminalResize { rows, cols, pixel_width: 0, pixel_height: 0 }
+}
+
+pub async fn publish(
+    client: &AsyncClient,
+    wire: &Wire,
+    topic: &str,
+    size: TerminalResize
+) -> anyhow::Result<()> {
+    client.publish(topic, QoS::AtMostOnce, false, wire.encode(Body::Resize(size))).await?;
+    Ok(())
+}
+
+/// Send the new size to the agent whenever the local window changes.
+/// `output` decides how much of the window the remote PTY gets.
+pub async fn watch(
+    client: AsyncClient,
+    wire: Arc<Wire>,
+    topic: String,
+    mut last: TerminalResize,
+    output: Arc<Mutex<Output>>
+) {
+    let mut winch = match signal(SignalKind::window_change()) {
+        Ok(winch) => winch,
+        Err(e) => {
+            eprintln!("❌ Cannot watch window size changes: {:?}", e);
+            return;
+        }
+    };
+    while winch.recv().await.is_some() {
+        while let Ok(Some(())) = timeout(DEBOUNCE, winch.recv()).await {}
+        let local = current();
+        if local != last {
+            last = local;
+            let size = match output.lock() {
+                Ok(mut output) => output.resize(local).unwrap_or(local),
+                Err(_) => local,
+            };
+            let _ = publish(&client, &wire, &topic, size).await;
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::streams::StreamMux;
+use mqttshell_proto::messages::StreamTarget;
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::sync::Arc;
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream };
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Accept SOCKS5 clients on `listener` and dial their CONNECT targets from the agent.
+pub async fn serve(mux: Arc<StreamMux>, listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let _ = socket.set_nodelay(true);
+        let mux = Arc::clone(&mux);
+        tokio::spawn(async move {
+            let _ = handle_client(mux, socket).await;
+        });
+    }
+}
+
+async fn handle_client(mux: Arc<StreamMux>, mut socket: TcpStream) -> anyhow::Result<()> {
+    let target = accept_connect(&mut socket).await?;
+    let stream = match mux.open(target).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            reply(&mut socket, REPLY_GENERAL_FAILURE).await?;
+            anyhow::bail!(e);
+        }
+    };
+    reply(&mut socket, REPLY_SUCCEEDED).await?;
+
+    let (reader, writer) = socket.into_split();
+    mux.pump(stream, reader, writer).await
+}
+
+/// Go through the method negotiation and read the client's request. Returns
+/// the target of a CONNECT; anything else is refused with a reply.
+async fn accept_connect<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S) -> anyhow::Result<StreamTarget> {
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        anyhow::bail!("unsupported SOCKS version {}", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&AUTH_NONE) {
+        socket.write_all(&[SOCKS_VERSION, AUTH_UNACCEPTABLE]).await?;
+        anyhow::bail!("client offered no acceptable authentication method");
+    }
+    socket.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
+
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    let [version, command, _, address_type] = request;
+    if version != SOCKS_VERSION {
+        anyhow::bail!("unsupported SOCKS version {}", version);
+    }
+
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            socket.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            socket.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let len = socket.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            socket.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        _ => {
+            reply(socket, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            anyhow::bail!("unsupported address type {}", address_type);
+        }
+    };
+    let port = socket.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        reply(socket, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        anyhow::bail!("unsupported SOCKS command {}", command);
+    }
+    Ok(StreamTarget::Tcp { host, port })
+}
+
+/// Send a reply; the bound address is not meaningful through the agent so it
+/// is always reported as 0.0.0.0:0.
+async fn reply<S: AsyncWrite + Unpin>(socket: &mut S, code: u8) -> std::io::Result<()> {
+    socket.write_all(&[SOCKS_VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Run `accept_connect` on what the client sends; returns its result
+    /// and everything written back.
+    async fn negotiate(client: &[u8]) -> (anyhow::Result<StreamTarget>, Vec<u8>) {
+        let (mut server, mut peer) = duplex(1024);
+        peer.write_all(client).await.unwrap();
+        peer.shutdown().await.unwrap();
+        let result = accept_connect(&mut server).await;
+        drop(server);
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+        (result, written)
+    }
+
+    fn tcp(target: StreamTarget) -> (String, u16) {
+        match target {
+            StreamTarget::Tcp { host, port } => (host, port),
+            other => panic!("unexpected target {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_to_ipv4() {
+        let (result, written) = negotiate(b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x1f\x90").await;
+        assert_eq!(tcp(result.unwrap()), ("127.0.0.1".to_string(), 8080));
+        assert_eq!(written, [SOCKS_VERSION, AUTH_NONE]);
+    }
+
+    #[tokio::test]
+    async fn connect_to_ipv6() {
+        let mut request = b"\x05\x01\x00\x05\x01\x00\x04".to_vec();
+        request.extend(Ipv6Addr::LOCALHOST.octets());
+        request.extend(443u16.to_be_bytes());
+        let (result, _) = negotiate(&request).await;
+        assert_eq!(tcp(result.unwrap()), ("::1".to_string(), 443));
+    }
+
+    #[tokio::test]
+    async fn connect_to_domain() {
+        // Among other methods, no authentication is picked
+        let (result, written) = negotiate(b"\x05\x02\x02\x00\x05\x01\x00\x03\x0bexample.com\x00\x50").await;
+        assert_eq!(tcp(result.unwrap()), ("example.com".to_string(), 80));
+        assert_eq!(written, [SOCKS_VERSION, AUTH_NONE]);
+    }
+
+    #[tokio::test]
+    async fn authentication_is_required() {
+        let (result, written) = negotiate(b"\x05\x01\x02").await;
+        assert!(result.is_err());
+        assert_eq!(written, [SOCKS_VERSION, AUTH_UNACCEPTABLE]);
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_refused() {
+        let (result, written) = negotiate(b"\x04\x01\x00\x50\x7f\x00\x00\x01\x00").await;
+        assert!(result.is_err());
+        assert!(written.is_empty());
+        let (result, _) = negotiate(b"\x05\x01\x00\x04\x01\x00\x01\x7f\x00\x00\x01\x00\x50").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn bind_is_not_supported() {
+        let (result, written) = negotiate(b"\x05\x01\x00\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x50").await;
+        assert!(result.is_err());
+        assert_eq!(written[2..4], [SOCKS_VERSION, REPLY_COMMAND_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn unknown_address_type() {
+        let (result, written) = negotiate(b"\x05\x01\x00\x05\x01\x00\x07").await;
+        assert!(result.is_err());
+        assert_eq!(written[2..4], [SOCKS_VERSION, REPLY_ADDRESS_NOT_SUPPORTED]);
+    }
+
+    #[tokio::test]
+    async fn truncated_and_invalid_requests() {
+        let (result, _) = negotiate(b"\x05\x01\x00\x05\x01\x00\x03\x0bexample").await;
+        assert!(result.is_err());
+        let (result, _) = negotiate(b"\x05\x01\x00\x05\x01\x00\x03\x02\xff\xfe\x00\x50").await;
+        assert!(result.is_err());
+    }
+}
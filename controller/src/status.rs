@@ -0,0 +1,273 @@
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+
+/// Where the controller shows its own state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatusMode {
+    /// Nothing is shown once the session started.
+    #[default]
+    Off,
+    /// The local terminal's window title.
+    Title,
+    /// A line reserved at the bottom of the local terminal.
+    Line,
+}
+
+impl StatusMode {
+    /// What to write to the local terminal if the controller exits without
+    /// tidying up after itself.
+    pub fn reset_sequence(&self) -> &'static str {
+        match self {
+            // Put back the title saved when the session started
+            StatusMode::Title => "\x1b[23;2t",
+            StatusMode::Off | StatusMode::Line => "",
+        }
+    }
+}
+
+/// State shown in the status title or line.
+#[derive(Debug, Default)]
+pub struct Status {
+    pub channel: String,
+    pub connected: bool,
+    /// Round trip of the last MQTT keep-alive ping.
+    pub latency: Option<Duration>,
+    /// Last message published by the agent on `<channel>/status`.
+    pub agent: Option<String>,
+    /// Payload compression and the ratio it achieves.
+    pub compression: Option<String>,
+    /// Last diagnostic message.
+    pub notice: Option<String>,
+}
+
+impl Status {
+    pub fn render(&self) -> String {
+        let mut parts = vec![
+            format!("mqttshell {}", self.channel),
+            (if self.connected { "connected" } else { "reconnecting..." }).to_string(),
+        ];
+        if let Some(latency) = self.latency {
+            parts.push(format!("{} ms", latency.as_millis()));
+        }
+        if let Some(agent) = &self.agent {
+            parts.push(format!("agent: {}", printable(agent)));
+        }
+        if let Some(compression) = &self.compression {
+            parts.push(compression.clone());
+        }
+        if let Some(notice) = &self.notice {
+            parts.push(printable(notice));
+        }
+        parts.join(" | ")
+    }
+}
+
+/// `text` without C0 and C1 control characters and DEL, which would end
+/// the title's OSC sequence or reach the local terminal as escape
+/// sequences. The agent's status comes from anyone who can publish on the
+/// channel.
+pub fn printable(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+const ESC: u8 = 0x1b;
+const MAX_SEQUENCE: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Keeps the bottom line of the local terminal out of the remote
+/// application's reach. The remote PTY is one row shorter than the local
+/// terminal and the scroll region stops above the status line; scroll
+/// region changes in the output are clamped to the remote height, and
+/// sequences that erase the status line mark it for redrawing. A shadow
+/// screen tracks the remote cursor and attributes so they can be restored
+/// after drawing the line without touching the remote's saved cursor.
+pub struct StatusLine {
+    rows: u16,
+    cols: u16,
+    state: State,
+    sequence: Vec<u8>,
+    screen: vt100::Parser,
+    damaged: bool,
+}
+
+impl StatusLine {
+    /// `rows` and `cols` are the size of the local terminal.
+    pub fn new(rows: u16, cols: u16) -> Self {
+        let rows = rows.max(2);
+        Self {
+            rows,
+            cols,
+            state: State::Ground,
+            sequence: Vec::new(),
+            screen: vt100::Parser::new(rows - 1, cols, 0),
+            damaged: true,
+        }
+    }
+
+    /// Height of the remote PTY.
+    pub fn remote_rows(&self) -> u16 {
+        self.rows - 1
+    }
+
+    /// Clear the local screen and reserve the bottom line.
+    pub fn start(&mut self) -> Vec<u8> {
+        self.damaged = true;
+        format!("\x1b[2J\x1b[H\x1b[1;{}r", self.remote_rows()).into_bytes()
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) -> Vec<u8> {
+        self.rows = rows.max(2);
+        self.cols = cols;
+        let remote_rows = self.remote_rows();
+        self.screen.screen_mut().set_size(remote_rows, cols);
+        self.damaged = true;
+        self.restore_margins()
+    }
+
+    /// Give the whole screen back and clear the status line.
+    pub fn stop(&self) -> Vec<u8> {
+        format!("\x1b[r\x1b[{};1H\x1b[0m\x1b[2K", self.rows).into_bytes()
+    }
+
+    /// Pass remote output through, clamping scroll regions to the remote
+    /// height. Incomplete escape sequences are held until the next call.
+    pub fn filter(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut fed = 0;
+        for &byte in bytes {
+            match (self.state, byte) {
+                (State::Ground, ESC) => {
+                    self.sequence.push(byte);
+                    self.state = State::Escape;
+                }
+                (State::Ground, _) => out.push(byte),
+                (State::Escape, b'[') => {
+                    self.sequence.push(byte);
+                    self.state = State::Csi;
+                }
+                (State::Escape, b'c') => {
+                    // RIS drops the scroll region and clears the screen
+                    self.sequence.push(byte);
+                    out.append(&mut self.sequence);
+                    self.screen.process(&out[fed..]);
+                    fed = out.len();
+                    out.extend(self.restore_margins());
+                    self.damaged = true;
+                    self.state = State::Ground;
+                }
+                (State::Escape, ESC) => {
+                    out.append(&mut self.sequence);
+                    self.sequence.push(byte);
+                }
+                (State::Escape, _) => {
+                    self.sequence.push(byte);
+                    out.append(&mut self.sequence);
+                    self.state = State::Ground;
+                }
+                (State::Csi, 0x40..=0x7e) => {
+                    self.sequence.push(byte);
+                    let sequence = std::mem::take(&mut self.sequence);
+                    if let Some(rewritten) = self.csi(&sequence) {
+                        out.extend(rewritten);
+                    } else {
+                        out.extend(&sequence);
+                        if sequence.ends_with(b"!p") {
+                            // DECSTR drops the scroll region as well
+                            self.screen.process(&out[fed..]);
+                            fed = out.len();
+                            out.extend(self.restore_margins());
+                        }
+                    }
+                    self.state = State::Ground;
+                }
+                (State::Csi, _) => {
+                    self.sequence.push(byte);
+                    if self.sequence.len() > MAX_SEQUENCE {
+                        out.append(&mut self.sequence);
+                        self.state = State::Ground;
+                    }
+                }
+            }
+        }
+        self.screen.process(&out[fed..]);
+        out
+    }
+
+    /// Rewrite a complete CSI sequence, or return `None` to pass it through.
+    fn csi(&mut self, sequence: &[u8]) -> Option<Vec<u8>> {
+        let params = &sequence[2..sequence.len() - 1];
+        let action = sequence[sequence.len() - 1];
+        match (params.first(), action) {
+            (Some(b'?'), b'h' | b'l') => {
+                let switches_screen = params[1..]
+                    .split(|&b| b == b';')
+                    .any(|p| matches!(p, b"47" | b"1047" | b"1049"));
+                if switches_screen {
+                    self.damaged = true;
+                }
+                None
+            }
+            (Some(b'0'..=b'9' | b';') | None, b'J') => {
+                self.damaged = true;
+                None
+            }
+            (Some(b'0'..=b'9' | b';') | None, b'r') if
+                params.iter().all(|b| b.is_ascii_digit() || *b == b';')
+            => {
+                let mut values = std::str::from_utf8(params)
+                    .ok()?
+                    .split(';')
+                    .map(|p| p.parse::<u16>().unwrap_or(0));
+                let top = values.next().unwrap_or(0).max(1);
+                let bottom = match values.next().unwrap_or(0) {
+                    0 => self.remote_rows(),
+                    bottom => bottom.min(self.remote_rows()),
+                };
+                Some(format!("\x1b[{};{}r", top, bottom).into_bytes())
+            }
+            _ => None,
+        }
+    }
+
+    /// Scroll region covering the remote rows, followed by the remote cursor
+    /// position, since setting the region homes the cursor.
+    fn restore_margins(&self) -> Vec<u8> {
+        let mut bytes = format!("\x1b[1;{}r", self.remote_rows()).into_bytes();
+        bytes.extend(self.screen.screen().cursor_state_formatted());
+        bytes.extend(self.screen.screen().attributes_formatted());
+        bytes
+    }
+
+    pub fn take_damaged(&mut self) -> bool {
+        std::mem::take(&mut self.damaged)
+    }
+
+    /// Draw `text` on the reserved line and put the cursor and attributes
+    /// back where the remote application left them.
+    pub fn draw(&self, text: &str) -> Vec<u8> {
+        let mut width = 0;
+        let text: String = text
+            .chars()
+            .take_while(|c| {
+                width += c.width().unwrap_or(0);
+                width <= (self.cols as usize)
+            })
+            .collect();
+        let padding = (self.cols as usize).saturating_sub(text.chars().map(|c| c.width().unwrap_or(0)).sum());
+        let mut bytes = format!(
+            "\x1b[{};1H\x1b[0;7m{}{}",
+            self.rows,
+            text,
+            " ".repeat(padding)
+        ).into_bytes();
+        bytes.extend(self.screen.screen().cursor_state_formatted());
+        bytes.extend(self.screen.screen().attributes_formatted());
+        bytes
+    }
+}
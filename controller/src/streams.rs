@@ -0,0 +1,236 @@
+use mqttshell_proto::envelope::{ Body, Wire };
+use mqttshell_proto::messages::{ StreamEvent, StreamOpen, StreamTarget };
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicBool, AtomicU32, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::sync::{ mpsc, oneshot };
+
+/// Data of a stream, or why the agent side of it failed.
+type Chunk = Result<Vec<u8>, String>;
+
+/// One stream opened through the agent. Incoming data arrives on `rx`; an
+/// empty chunk means the agent side reached end of stream.
+pub struct MuxStream {
+    pub id: String,
+    rx: mpsc::Receiver<Chunk>,
+}
+
+/// Client side of the MQTT stream multiplexer.
+pub struct StreamMux {
+    client: AsyncClient,
+    wire: Arc<Wire>,
+    topic_open: String,
+    topic_event: String,
+    topic_prefix: String,
+    id_base: String,
+    next_id: AtomicU32,
+    subscribed: AtomicBool,
+    pending: Mutex<HashMap<String, oneshot::Sender<Result<(), String>>>>,
+    readers: Mutex<HashMap<String, mpsc::Sender<Chunk>>>,
+}
+
+const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Chunks received for a stream that the local side has not taken yet. A
+/// stream whose local side falls further behind is closed.
+const STREAM_BUFFER: usize = 256;
+
+impl StreamMux {
+    pub fn new(client: AsyncClient, channel: &str, wire: Arc<Wire>) -> Arc<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        Arc::new(Self {
+            client,
+            wire,
+            topic_open: format!("{}/stream/open", channel),
+            topic_event: format!("{}/stream/event", channel),
+            topic_prefix: format!("{}/stream/", channel),
+            id_base: format!("{:x}{:08x}", std::process::id(), nanos),
+            next_id: AtomicU32::new(0),
+            subscribed: AtomicBool::new(false),
+            pending: Mutex::new(HashMap::new()),
+            readers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn subscribe(&self) -> anyhow::Result<()> {
+        self.client.subscribe(&self.topic_event, QoS::AtLeastOnce).await?;
+        self.subscribed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Subscribe again to the event topic and the topics of open streams,
+    /// after a reconnect with a clean session dropped them.
+    pub async fn resubscribe(&self) -> anyhow::Result<()> {
+        if !self.subscribed.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.client.subscribe(&self.topic_event, QoS::AtLeastOnce).await?;
+        let ids: Vec<String> = self.readers
+            .lock()
+            .map(|readers| readers.keys().cloned().collect())
+            .unwrap_or_default();
+        for id in ids {
+            let topic_down = format!("{}{}/down", self.topic_prefix, id);
+            self.client.subscribe(&topic_down, QoS::AtLeastOnce).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a publish if it belongs to the stream layer.
+    pub fn dispatch(&self, topic: &str, payload: &[u8]) -> bool {
+        if topic == self.topic_event {
+            let (id, result) = match self.wire.decode_stream_event(payload) {
+                Ok(StreamEvent::Opened { id }) => (id, Ok(())),
+                Ok(StreamEvent::Failed { id, error }) => (id, Err(error)),
+                Err(_) => {
+                    return true;
+                }
+            };
+            if let Some(waiter) = self.pending.lock().ok().and_then(|mut p| p.remove(&id)) {
+                let _ = waiter.send(result);
+            } else if let Err(error) = result {
+                // The agent side of an open stream failed
+                self.deliver(&id, Err(error));
+            }
+            return true;
+        }
+
+        let id = match
+            topic.strip_prefix(&self.topic_prefix).and_then(|rest| rest.strip_suffix("/down"))
+        {
+            Some(id) => id,
+            None => {
+                return false;
+            }
+        };
+        if let Ok(data) = self.wire.decode_data(payload) {
+            self.deliver(id, Ok(data));
+        }
+        true
+    }
+
+    fn deliver(&self, id: &str, chunk: Chunk) {
+        let Some(reader) = self.readers.lock().ok().and_then(|r| r.get(id).cloned()) else {
+            return;
+        };
+        // Dropping the sender ends the stream's pump
+        if let Err(mpsc::error::TrySendError::Full(_)) = reader.try_send(chunk) {
+            if let Ok(mut readers) = self.readers.lock() {
+                readers.remove(id);
+            }
+        }
+    }
+
+    /// Ask the agent to open `target` and wait for its answer.
+    pub async fn open(&self, target: StreamTarget) -> Result<MuxStream, String> {
+        let id = format!("{}-{}", self.id_base, self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let (data_tx, data_rx) = mpsc::channel(STREAM_BUFFER);
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(id.clone(), opened_tx);
+        }
+        if let Ok(mut readers) = self.readers.lock() {
+            readers.insert(id.clone(), data_tx);
+        }
+
+        let request = StreamOpen { id: id.clone(), target };
+        let topic_down = format!("{}{}/down", self.topic_prefix, id);
+        let payload = self.wire.encode(Body::StreamOpen(request));
+        let result = if self.client.subscribe(&topic_down, QoS::AtLeastOnce).await.is_err() {
+            Err("broker connection closed".to_string())
+        } else {
+            match self.client.publish(&self.topic_open, QoS::AtLeastOnce, false, payload).await {
+                Ok(_) =>
+                    match tokio::time::timeout(OPEN_TIMEOUT, opened_rx).await {
+                        Ok(Ok(result)) => result,
+                        Ok(Err(_)) => Err("stream layer shut down".to_string()),
+                        Err(_) => Err("agent did not answer".to_string()),
+                    }
+                Err(e) => Err(e.to_string()),
+            }
+        };
+
+        match result {
+            Ok(()) => Ok(MuxStream { id, rx: data_rx }),
+            Err(e) => {
+                self.forget(&id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Copy data both ways between `stream` and a local reader/writer pair.
+    /// Local end of file is passed on as a half-close; the call returns once
+    /// the agent side of the stream has closed, with an error unless both
+    /// directions ended cleanly.
+    pub async fn pump<R, W>(&self, stream: MuxStream, mut reader: R, mut writer: W) -> anyhow::Result<()>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let MuxStream { id, mut rx } = stream;
+        let topic_up = format!("{}{}/up", self.topic_prefix, id);
+
+        let upstream = async {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                let payload = self.wire.encode(Body::Data(buf[..n].to_vec()));
+                self.client.publish(&topic_up, QoS::AtLeastOnce, false, payload).await?;
+            }
+            let payload = self.wire.encode(Body::Data(Vec::new()));
+            self.client.publish(&topic_up, QoS::AtLeastOnce, false, payload).await?;
+            anyhow::Ok(())
+        };
+
+        let downstream = async {
+            let ended = loop {
+                match rx.recv().await {
+                    Some(Ok(data)) if data.is_empty() => {
+                        break Ok(());
+                    }
+                    Some(Ok(data)) => {
+                        writer.write_all(&data).await?;
+                        writer.flush().await?;
+                    }
+                    Some(Err(error)) => {
+                        break Err(anyhow::anyhow!("the agent side failed: {}", error));
+                    }
+                    None => {
+                        break Err(anyhow::anyhow!("the stream fell behind and was closed"));
+                    }
+                }
+            };
+            let _ = writer.shutdown().await;
+            ended
+        };
+
+        tokio::pin!(upstream, downstream);
+        let result = tokio::select! {
+            result = &mut downstream => result,
+            result = &mut upstream => match result {
+                Ok(()) => downstream.await,
+                Err(e) => Err(e),
+            },
+        };
+        self.forget(&id);
+        result
+    }
+
+    fn forget(&self, id: &str) {
+        let _ = self.client.try_unsubscribe(format!("{}{}/down", self.topic_prefix, id));
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(id);
+        }
+        if let Ok(mut readers) = self.readers.lock() {
+            readers.remove(id);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use crate::streams::StreamMux;
+use mqttshell_proto::messages::StreamTarget;
+use std::path::Path;
+
+/// Upload the local file at `path` into the agent's upload directory, under
+/// the same file name, over a stream. Returns the name and the number of
+/// bytes sent once the agent has stored the whole file.
+pub async fn send(mux: &StreamMux, path: &Path) -> anyhow::Result<(String, u64)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("{} has no file name", path.display()))?
+        .to_string();
+    let file = tokio::fs::File::open(path).await.map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        anyhow::bail!("{} is not a regular file", path.display());
+    }
+
+    // Without forwarding, nothing has subscribed to stream events yet
+    mux.subscribe().await?;
+    let stream = mux.open(StreamTarget::File { name: name.clone() }).await.map_err(|e| anyhow::anyhow!(e))?;
+    mux.pump(stream, file, tokio::io::sink()).await?;
+    Ok((name, metadata.len()))
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+use std::io::{ self, Write };
+use std::str::FromStr;
+
+/// Compression applied to the payloads of a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Zstd,
+    Deflate,
+}
+
+impl Algorithm {
+    /// Every algorithm, most preferred first.
+    pub const ALL: [Algorithm; 2] = [Algorithm::Zstd, Algorithm::Deflate];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::Deflate => "deflate",
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zstd" => Ok(Algorithm::Zstd),
+            "deflate" => Ok(Algorithm::Deflate),
+            _ => anyhow::bail!("unknown compression '{}', expected zstd or deflate", s),
+        }
+    }
+}
+
+/// Control command a controller sends to offer compression, listing the
+/// algorithms it accepts, most preferred first.
+pub fn offer(algorithms: &[Algorithm]) -> String {
+    let names: Vec<&str> = algorithms.iter().map(Algorithm::name).collect();
+    format!("compress {}", names.join(" ")).trim_end().to_string()
+}
+
+/// The algorithms of an offer, unknown ones left out, or `None` if
+/// `command` is not an offer.
+pub fn parse_offer(command: &str) -> Option<Vec<Algorithm>> {
+    let names = command.strip_prefix("compress")?;
+    if !names.is_empty() && !names.starts_with(' ') {
+        return None;
+    }
+    Some(names.split_whitespace().filter_map(|name| name.parse().ok()).collect())
+}
+
+/// Status an agent publishes in reply to an offer: the algorithm chosen
+/// and the epoch tagging every compressed payload from now on, or `none`.
+pub fn accept(chosen: Option<(Algorithm, u8)>) -> String {
+    match chosen {
+        Some((algorithm, epoch)) => format!("compress {} {}", algorithm, epoch),
+        None => "compress none".to_string(),
+    }
+}
+
+/// The algorithm and epoch of an agent's reply; `Some(None)` when it
+/// declined, `None` if `status` is not a reply.
+pub fn parse_accept(status: &str) -> Option<Option<(Algorithm, u8)>> {
+    let reply = status.strip_prefix("compress ")?;
+    if reply == "none" {
+        return Some(None);
+    }
+    let (algorithm, epoch) = reply.split_once(' ')?;
+    Some(Some((algorithm.parse().ok()?, epoch.parse().ok()?)))
+}
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(flate2::write::DeflateEncoder<Vec<u8>>),
+}
+
+/// One direction of a compressed stream. Every message is flushed on its
+/// own, but the compression window carries over from the previous ones, so
+/// even a single keystroke or prompt compresses well once the session has
+/// seen similar data.
+pub struct Compressor {
+    encoder: Encoder,
+}
+
+impl Compressor {
+    pub fn new(algorithm: Algorithm) -> io::Result<Self> {
+        let encoder = match algorithm {
+            Algorithm::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            Algorithm::Deflate => {
+                Encoder::Deflate(
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default())
+                )
+            }
+        };
+        Ok(Self { encoder })
+    }
+
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match &mut self.encoder {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+}
+
+enum Decoder {
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    Deflate(flate2::write::DeflateDecoder<Vec<u8>>),
+}
+
+/// Reverses a [`Compressor`], given its messages in order and none missing.
+pub struct Decompressor {
+    decoder: Decoder,
+}
+
+impl Decompressor {
+    pub fn new(algorithm: Algorithm) -> io::Result<Self> {
+        let decoder = match algorithm {
+            Algorithm::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+            Algorithm::Deflate => Decoder::Deflate(flate2::write::DeflateDecoder::new(Vec::new())),
+        };
+        Ok(Self { decoder })
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match &mut self.decoder {
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+}
+
+/// Bytes before and after compression.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ratio {
+    pub raw: u64,
+    pub compressed: u64,
+}
+
+impl Ratio {
+    pub fn add(&mut self, raw: usize, compressed: usize) {
+        self.raw += raw as u64;
+        self.compressed += compressed as u64;
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.compressed == 0 {
+            return f.write_str("-");
+        }
+        write!(f, "{:.1}:1", self.raw as f64 / self.compressed as f64)
+    }
+}
+
+/// Compresses the messages sent in one direction of a session. Each
+/// payload starts with the epoch the agent chose for the session and a
+/// sequence number, so the receiver can ignore payloads from an earlier
+/// session and notice lost ones, which the stream cannot recover from.
+pub struct Sealer {
+    epoch: u8,
+    sequence: u8,
+    compressor: Compressor,
+    pub ratio: Ratio,
+}
+
+impl Sealer {
+    pub fn new(algorithm: Algorithm, epoch: u8) -> io::Result<Self> {
+        Ok(Self {
+            epoch,
+            sequence: 0,
+            compressor: Compressor::new(algorithm)?,
+            ratio: Ratio::default(),
+        })
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut payload = vec![self.epoch, self.sequence];
+        payload.extend(self.compressor.compress(data)?);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.ratio.add(data.len(), payload.len());
+        Ok(payload)
+    }
+}
+
+/// Receiving end of a [`Sealer`].
+pub struct Opener {
+    algorithm: Algorithm,
+    epoch: u8,
+    sequence: u8,
+    decompressor: Decompressor,
+    pub ratio: Ratio,
+}
+
+impl Opener {
+    pub fn new(algorithm: Algorithm, epoch: u8) -> io::Result<Self> {
+        Ok(Self {
+            algorithm,
+            epoch,
+            sequence: 0,
+            decompressor: Decompressor::new(algorithm)?,
+            ratio: Ratio::default(),
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// The data of `payload`, or `None` if it belongs to another epoch.
+    /// Fails if payloads went missing or the data is corrupt; the stream
+    /// has to be negotiated again then.
+    pub fn open(&mut self, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let [epoch, sequence, data @ ..] = payload else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated payload"));
+        };
+        if *epoch != self.epoch {
+            return Ok(None);
+        }
+        if *sequence != self.sequence {
+            return Err(
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("expected payload {}, got {}", self.sequence, sequence)
+                )
+            );
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        let data = self.decompressor.decompress(data)?;
+        self.ratio.add(data.len(), payload.len());
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offer_round_trips() {
+        let command = offer(&Algorithm::ALL);
+        assert_eq!(command, "compress zstd deflate");
+        assert_eq!(parse_offer(&command), Some(Algorithm::ALL.to_vec()));
+        assert_eq!(parse_offer(&offer(&[])), Some(Vec::new()));
+        assert_eq!(parse_offer("compress lz4 deflate"), Some(vec![Algorithm::Deflate]));
+        assert_eq!(parse_offer("compression zstd"), None);
+        assert_eq!(parse_offer("refresh"), None);
+    }
+
+    #[test]
+    fn accept_round_trips() {
+        let chosen = Some((Algorithm::Zstd, 42));
+        assert_eq!(parse_accept(&accept(chosen)), Some(chosen));
+        assert_eq!(parse_accept(&accept(None)), Some(None));
+        assert_eq!(parse_accept("compress zstd"), None);
+        assert_eq!(parse_accept("compress zstd 256"), None);
+        assert_eq!(parse_accept("shell_ready"), None);
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        for algorithm in Algorithm::ALL {
+            let mut compressor = Compressor::new(algorithm).unwrap();
+            let mut decompressor = Decompressor::new(algorithm).unwrap();
+            for message in [&b"user@host:~$ "[..], b"ls -l\r\n", b"", &[0xff; 10_000]] {
+                let compressed = compressor.compress(message).unwrap();
+                assert_eq!(decompressor.decompress(&compressed).unwrap(), message, "{}", algorithm);
+            }
+        }
+    }
+
+    #[test]
+    fn window_carries_over() {
+        let prompt = b"user@host:~/projects/mqttshell$ ";
+        let mut compressor = Compressor::new(Algorithm::Zstd).unwrap();
+        let first = compressor.compress(prompt).unwrap();
+        let second = compressor.compress(prompt).unwrap();
+        assert!(second.len() < first.len());
+    }
+
+    #[test]
+    fn sealed_payloads_open_in_order() {
+        let mut sealer = Sealer::new(Algorithm::Deflate, 7).unwrap();
+        let mut opener = Opener::new(Algorithm::Deflate, 7).unwrap();
+        for i in 0..300u32 {
+            let data = format!("line {}\r\n", i);
+            let payload = sealer.seal(data.as_bytes()).unwrap();
+            assert_eq!(payload[0], 7);
+            assert_eq!(payload[1], i as u8);
+            assert_eq!(opener.open(&payload).unwrap(), Some(data.into_bytes()));
+        }
+        assert_eq!(sealer.ratio.raw, opener.ratio.raw);
+    }
+
+    #[test]
+    fn other_epoch_is_ignored() {
+        let mut old = Sealer::new(Algorithm::Zstd, 1).unwrap();
+        let mut sealer = Sealer::new(Algorithm::Zstd, 2).unwrap();
+        let mut opener = Opener::new(Algorithm::Zstd, 2).unwrap();
+        assert_eq!(opener.open(&old.seal(b"stale").unwrap()).unwrap(), None);
+        // An ignored payload does not count towards the sequence
+        assert_eq!(opener.open(&sealer.seal(b"fresh").unwrap()).unwrap(), Some(b"fresh".to_vec()));
+    }
+
+    #[test]
+    fn missing_payload_fails() {
+        let mut sealer = Sealer::new(Algorithm::Zstd, 3).unwrap();
+        let mut opener = Opener::new(Algorithm::Zstd, 3).unwrap();
+        opener.open(&sealer.seal(b"one").unwrap()).unwrap();
+        sealer.seal(b"two").unwrap();
+        let error = opener.open(&sealer.seal(b"three").unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_payload_fails() {
+        let mut opener = Opener::new(Algorithm::Deflate, 0).unwrap();
+        assert!(opener.open(&[0]).is_err());
+        assert!(opener.open(&[]).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use crate::handshake::{ Hello, Welcome };
+use crate::messages::{ StreamEvent, StreamOpen, TerminalResize };
+use crate::session::SessionOpen;
+use serde::{ Deserialize, Serialize };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+/// Envelope version written by this build. Receivers refuse envelopes with
+/// a higher version; fields added without bumping it must be optional.
+pub const VERSION: u8 = 1;
+
+/// How payloads are encoded on every topic. All agents and controllers of
+/// a deployment have to use the same format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// No envelope: raw bytes for terminal and stream data, JSON for resize,
+    /// handshake, session and stream control, plain text for status and
+    /// control commands, as understood by older builds.
+    #[default]
+    Raw,
+    /// A CBOR encoded [`Envelope`].
+    Cbor,
+    /// A MessagePack encoded [`Envelope`].
+    Msgpack,
+}
+
+/// What a topic carries, needed to make sense of raw payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// `in`, `out`, their compressed variants and stream data.
+    Data,
+    Resize,
+    Status,
+    Control,
+    StreamOpen,
+    StreamEvent,
+    Hello,
+    Welcome,
+    Open,
+}
+
+/// A payload with the metadata raw payloads have no room for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub v: u8,
+    /// Identifies the sender's session: the shell instance for the agent,
+    /// the process for controllers.
+    #[serde(default)]
+    pub session: u64,
+    /// Counts the sender's envelopes across all topics.
+    #[serde(default)]
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch when the envelope was made.
+    #[serde(default)]
+    pub ts: u64,
+    pub body: Body,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Body {
+    Data(#[serde(with = "serde_bytes")] Vec<u8>),
+    Resize(TerminalResize),
+    Status(String),
+    Control(String),
+    StreamOpen(StreamOpen),
+    StreamEvent(StreamEvent),
+    Hello(Hello),
+    Welcome(Welcome),
+    Open(SessionOpen),
+}
+
+impl Body {
+    fn kind(&self) -> Kind {
+        match self {
+            Body::Data(_) => Kind::Data,
+            Body::Resize(_) => Kind::Resize,
+            Body::Status(_) => Kind::Status,
+            Body::Control(_) => Kind::Control,
+            Body::StreamOpen(_) => Kind::StreamOpen,
+            Body::StreamEvent(_) => Kind::StreamEvent,
+            Body::Hello(_) => Kind::Hello,
+            Body::Welcome(_) => Kind::Welcome,
+            Body::Open(_) => Kind::Open,
+        }
+    }
+}
+
+/// Encodes and decodes payloads in the deployment's format, numbering the
+/// envelopes sent.
+pub struct Wire {
+    format: Format,
+    session: AtomicU64,
+    seq: AtomicU64,
+}
+
+impl Wire {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            session: AtomicU64::new(new_session_id()),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Start a new session, e.g. when the agent restarts the shell.
+    pub fn new_session(&self) {
+        self.session.store(new_session_id(), Ordering::Relaxed);
+    }
+
+    pub fn encode(&self, body: Body) -> Vec<u8> {
+        if self.format == Format::Raw {
+            return raw_payload(body);
+        }
+        let envelope = Envelope {
+            v: VERSION,
+            session: self.session.load(Ordering::Relaxed),
+            seq: self.seq.fetch_add(1, Ordering::Relaxed),
+            ts: now_millis(),
+            body,
+        };
+        // Serializing into memory cannot fail for any of the bodies
+        if self.format == Format::Cbor {
+            let mut payload = Vec::new();
+            ciborium::into_writer(&envelope, &mut payload).expect("envelope serializes");
+            payload
+        } else {
+            rmp_serde::to_vec_named(&envelope).expect("envelope serializes")
+        }
+    }
+
+    /// Decode a payload received on a topic carrying `kind`. Raw payloads
+    /// come back in an envelope of version 0 without metadata.
+    pub fn decode(&self, kind: Kind, payload: &[u8]) -> anyhow::Result<Envelope> {
+        let envelope = match self.format {
+            Format::Raw => {
+                let body = match kind {
+                    Kind::Data => Body::Data(payload.to_vec()),
+                    Kind::Status => Body::Status(String::from_utf8_lossy(payload).into_owned()),
+                    Kind::Control => Body::Control(String::from_utf8_lossy(payload).into_owned()),
+                    Kind::Resize => Body::Resize(serde_json::from_slice(payload)?),
+                    Kind::StreamOpen => Body::StreamOpen(serde_json::from_slice(payload)?),
+                    Kind::StreamEvent => Body::StreamEvent(serde_json::from_slice(payload)?),
+                    Kind::Hello => Body::Hello(serde_json::from_slice(payload)?),
+                    Kind::Welcome => Body::Welcome(serde_json::from_slice(payload)?),
+                    Kind::Open => Body::Open(serde_json::from_slice(payload)?),
+                };
+                return Ok(Envelope { v: 0, session: 0, seq: 0, ts: 0, body });
+            }
+            Format::Cbor => ciborium::from_reader::<Envelope, _>(payload)?,
+            Format::Msgpack => rmp_serde::from_slice::<Envelope>(payload)?,
+        };
+        if envelope.v > VERSION {
+            anyhow::bail!("envelope version {} is newer than {}", envelope.v, VERSION);
+        }
+        if envelope.body.kind() != kind {
+            anyhow::bail!("expected a {:?} envelope, got {:?}", kind, envelope.body.kind());
+        }
+        Ok(envelope)
+    }
+
+    pub fn decode_data(&self, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self.decode(Kind::Data, payload)?.body {
+            Body::Data(data) => Ok(data),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    /// A status message or control command.
+    pub fn decode_text(&self, kind: Kind, payload: &[u8]) -> anyhow::Result<String> {
+        match self.decode(kind, payload)?.body {
+            Body::Status(text) | Body::Control(text) => Ok(text),
+            _ => anyhow::bail!("{:?} is not a text kind", kind),
+        }
+    }
+
+    pub fn decode_resize(&self, payload: &[u8]) -> anyhow::Result<TerminalResize> {
+        match self.decode(Kind::Resize, payload)?.body {
+            Body::Resize(size) => Ok(size),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    pub fn decode_stream_open(&self, payload: &[u8]) -> anyhow::Result<StreamOpen> {
+        match self.decode(Kind::StreamOpen, payload)?.body {
+            Body::StreamOpen(open) => Ok(open),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    pub fn decode_stream_event(&self, payload: &[u8]) -> anyhow::Result<StreamEvent> {
+        match self.decode(Kind::StreamEvent, payload)?.body {
+            Body::StreamEvent(event) => Ok(event),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    pub fn decode_hello(&self, payload: &[u8]) -> anyhow::Result<Hello> {
+        match self.decode(Kind::Hello, payload)?.body {
+            Body::Hello(hello) => Ok(hello),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    pub fn decode_welcome(&self, payload: &[u8]) -> anyhow::Result<Welcome> {
+        match self.decode(Kind::Welcome, payload)?.body {
+            Body::Welcome(welcome) => Ok(welcome),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+
+    pub fn decode_open(&self, payload: &[u8]) -> anyhow::Result<SessionOpen> {
+        match self.decode(Kind::Open, payload)?.body {
+            Body::Open(open) => Ok(open),
+            _ => unreachable!("decode checks the kind"),
+        }
+    }
+}
+
+/// A body as older builds expect it on its topic.
+fn raw_payload(body: Body) -> Vec<u8> {
+    match body {
+        Body::Data(data) => data,
+        Body::Status(text) | Body::Control(text) => text.into_bytes(),
+        Body::Resize(size) => serde_json::to_vec(&size).expect("resize serializes"),
+        Body::StreamOpen(open) => serde_json::to_vec(&open).expect("stream open serializes"),
+        Body::StreamEvent(event) => serde_json::to_vec(&event).expect("stream event serializes"),
+        Body::Hello(hello) => serde_json::to_vec(&hello).expect("hello serializes"),
+        Body::Welcome(welcome) => serde_json::to_vec(&welcome).expect("welcome serializes"),
+        Body::Open(open) => serde_json::to_vec(&open).expect("session open serializes"),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn new_session_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    nanos ^ ((std::process::id() as u64) << 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [Format; 3] = [Format::Raw, Format::Cbor, Format::Msgpack];
+
+    fn size() -> TerminalResize {
+        TerminalResize { rows: 24, cols: 80, pixel_width: 640, pixel_height: 384 }
+    }
+
+    fn serialize(format: Format, envelope: &impl Serialize) -> Vec<u8> {
+        match format {
+            Format::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(envelope, &mut payload).unwrap();
+                payload
+            }
+            Format::Msgpack => rmp_serde::to_vec_named(envelope).unwrap(),
+            Format::Raw => unreachable!("raw payloads have no envelope"),
+        }
+    }
+
+    #[test]
+    fn bodies_round_trip() {
+        for format in FORMATS {
+            let wire = Wire::new(format);
+            let data = vec![0, 0x1b, b'[', 0xff, b'\n'];
+            assert_eq!(wire.decode_data(&wire.encode(Body::Data(data.clone()))).unwrap(), data);
+            let status = wire.encode(Body::Status("shell_ready".to_string()));
+            assert_eq!(wire.decode_text(Kind::Status, &status).unwrap(), "shell_ready");
+            let control = wire.encode(Body::Control("refresh".to_string()));
+            assert_eq!(wire.decode_text(Kind::Control, &control).unwrap(), "refresh");
+            assert_eq!(wire.decode_resize(&wire.encode(Body::Resize(size()))).unwrap(), size());
+            let event = StreamEvent::Failed { id: "s1".to_string(), error: "refused".to_string() };
+            let decoded = wire.decode_stream_event(&wire.encode(Body::StreamEvent(event))).unwrap();
+            assert!(matches!(decoded, StreamEvent::Failed { id, error } if id == "s1" && error == "refused"));
+        }
+    }
+
+    #[test]
+    fn raw_payloads_are_what_older_builds_send() {
+        let wire = Wire::new(Format::Raw);
+        assert_eq!(wire.encode(Body::Data(b"ls\r".to_vec())), b"ls\r");
+        assert_eq!(wire.encode(Body::Status("echo_off".to_string())), b"echo_off");
+        let resize: TerminalResize = serde_json::from_slice(&wire.encode(Body::Resize(size()))).unwrap();
+        assert_eq!(resize, size());
+        let legacy = wire.decode_resize(br#"{"rows":30,"cols":100}"#).unwrap();
+        assert_eq!((legacy.rows, legacy.cols, legacy.pixel_width), (30, 100, 0));
+        let envelope = wire.decode(Kind::Data, b"output").unwrap();
+        assert_eq!((envelope.v, envelope.session, envelope.seq), (0, 0, 0));
+    }
+
+    #[test]
+    fn envelopes_are_numbered() {
+        for format in [Format::Cbor, Format::Msgpack] {
+            let wire = Wire::new(format);
+            let first = wire.decode(Kind::Data, &wire.encode(Body::Data(Vec::new()))).unwrap();
+            let second = wire.decode(Kind::Status, &wire.encode(Body::Status(String::new()))).unwrap();
+            assert_eq!(first.v, VERSION);
+            assert_eq!((first.seq, second.seq), (0, 1));
+            assert_eq!(first.session, second.session);
+            assert!(first.ts > 0);
+        }
+    }
+
+    #[test]
+    fn wrong_kind_is_refused() {
+        for format in [Format::Cbor, Format::Msgpack] {
+            let wire = Wire::new(format);
+            let status = wire.encode(Body::Status("agent_online".to_string()));
+            assert!(wire.decode_data(&status).is_err());
+            assert!(wire.decode_text(Kind::Control, &status).is_err());
+            assert!(wire.decode_resize(&wire.encode(Body::Data(b"{}".to_vec()))).is_err());
+        }
+    }
+
+    #[test]
+    fn newer_version_is_refused() {
+        for format in [Format::Cbor, Format::Msgpack] {
+            let wire = Wire::new(format);
+            let envelope = Envelope { v: VERSION + 1, session: 1, seq: 0, ts: 0, body: Body::Data(Vec::new()) };
+            let error = wire.decode_data(&serialize(format, &envelope)).unwrap_err();
+            assert!(error.to_string().contains("newer"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn metadata_is_optional() {
+        #[derive(Serialize)]
+        struct Minimal {
+            v: u8,
+            body: Body,
+        }
+        for format in [Format::Cbor, Format::Msgpack] {
+            let wire = Wire::new(format);
+            let payload = serialize(format, &Minimal { v: 0, body: Body::Control("hangup".to_string()) });
+            let envelope = wire.decode(Kind::Control, &payload).unwrap();
+            assert_eq!((envelope.session, envelope.seq, envelope.ts), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn garbage_is_refused() {
+        for format in [Format::Cbor, Format::Msgpack] {
+            assert!(Wire::new(format).decode_data(b"\xff\x00garbage").is_err());
+        }
+        assert!(Wire::new(Format::Raw).decode_resize(b"24x80").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{ Deserialize, Serialize };
+use std::fmt;
+
+/// Protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest protocol version this build still talks to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Optional feature a side may support. Features are only used when both
+/// sides announce them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Payload compression, see [`crate::compress`].
+    Compression,
+    /// End-to-end encrypted payloads.
+    Encryption,
+    /// Running single commands instead of the interactive shell.
+    Exec,
+    /// TCP and Unix socket streams dialled by the agent.
+    Forwarding,
+    /// Whole-screen snapshots from the agent's own terminal emulator.
+    Snapshot,
+    /// Shells spawned on a controller's [`crate::session::SessionOpen`].
+    Session,
+}
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Compression => "compression",
+            Capability::Encryption => "encryption",
+            Capability::Exec => "exec",
+            Capability::Forwarding => "forwarding",
+            Capability::Snapshot => "snapshot",
+            Capability::Session => "session",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Published by a controller on `<channel>/hello` when it attaches.
+/// Capabilities travel as names, so that newer ones are skipped rather
+/// than rejected.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    /// Chosen by the controller and echoed in the welcome, since every
+    /// controller on the channel receives it.
+    pub id: String,
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Vec<String>,
+}
+
+/// The agent's answer on `<channel>/welcome`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub id: String,
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Vec<String>,
+    /// Why the agent refuses the controller.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Profiles a controller may ask for when opening a session.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    /// Users a controller may ask to run the session as.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Whether a shell already runs, which session requests do not change.
+    #[serde(default)]
+    pub running: bool,
+}
+
+/// Names of `capabilities`, as announced.
+pub fn names(capabilities: &[Capability]) -> Vec<String> {
+    capabilities.iter().map(|capability| capability.name().to_string()).collect()
+}
+
+/// The capabilities in `ours` the peer announced as well.
+pub fn common(ours: &[Capability], theirs: &[String]) -> Vec<Capability> {
+    ours.iter()
+        .copied()
+        .filter(|capability| theirs.iter().any(|name| name == capability.name()))
+        .collect()
+}
+
+/// Why the `peer`, speaking `version` and accepting `min_version` and
+/// later, cannot talk to this build, the `ours` side, if it cannot.
+pub fn incompatibility(ours: &str, peer: &str, version: u16, min_version: u16) -> Option<String> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Some(
+            format!(
+                "the {} speaks protocol version {} but the {} needs {} or later, upgrade the {}",
+                peer,
+                version,
+                ours,
+                MIN_PROTOCOL_VERSION,
+                peer
+            )
+        );
+    }
+    if PROTOCOL_VERSION < min_version {
+        return Some(
+            format!(
+                "the {} needs protocol version {} or later but the {} speaks {}, upgrade the {}",
+                peer,
+                min_version,
+                ours,
+                PROTOCOL_VERSION,
+                ours
+            )
+        );
+    }
+    None
+}
+
+/// The agent's answer to `hello`, given the agent's capabilities, profiles
+/// and users.
+pub fn welcome(hello: &Hello, capabilities: &[Capability], profiles: &[String], users: &[String]) -> Welcome {
+    Welcome {
+        id: hello.id.clone(),
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        capabilities: names(capabilities),
+        error: incompatibility("agent", "controller", hello.version, hello.min_version),
+        profiles: profiles.to_vec(),
+        users: users.to_vec(),
+        running: false,
+    }
+}
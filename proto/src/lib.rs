@@ -0,0 +1,5 @@
+pub mod compress;
+pub mod envelope;
+pub mod handshake;
+pub mod messages;
+pub mod session;
@@ -0,0 +1,63 @@
+use serde::{ Deserialize, Serialize };
+
+/// Size of the remote PTY, published by controllers on `<channel>/resize`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalResize {
+    pub rows: u16,
+    pub cols: u16,
+    /// Window size in pixels, zero when the controller's terminal does not
+    /// report it.
+    #[serde(default)]
+    pub pixel_width: u16,
+    #[serde(default)]
+    pub pixel_height: u16,
+}
+
+impl TerminalResize {
+    /// This size with at least one row and column, as a controller that is
+    /// not on a terminal may report 0x0.
+    pub fn at_least_one_cell(self) -> Self {
+        Self {
+            rows: self.rows.max(1),
+            cols: self.cols.max(1),
+            ..self
+        }
+    }
+}
+
+/// Request published on `<channel>/stream/open` asking the agent to dial a target.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamOpen {
+    pub id: String,
+    pub target: StreamTarget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StreamTarget {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    Unix {
+        path: String,
+    },
+    /// A new file in the agent's upload directory. Data sent up the stream
+    /// is stored in it, the agent closes its side once the file is complete.
+    File {
+        name: String,
+    },
+}
+
+/// Answer published by the agent on `<channel>/stream/event`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Opened {
+        id: String,
+    },
+    Failed {
+        id: String,
+        error: String,
+    },
+}
@@ -0,0 +1,49 @@
+use crate::messages::TerminalResize;
+use serde::{ Deserialize, Serialize };
+use std::collections::BTreeMap;
+
+/// Variables every controller sends and every agent accepts: how to talk
+/// to the controller's terminal, and the user's locale.
+pub const TERMINAL_ENV: [&str; 4] = ["TERM", "COLORTERM", "LANG", "LC_*"];
+
+/// Published by a controller on `<channel>/open` to have the agent spawn
+/// the shell for it. Agents that already run a shell ignore it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionOpen {
+    pub size: TerminalResize,
+    /// The controller's [`TERMINAL_ENV`] and the extra variables it was
+    /// told to send; the agent drops those it does not accept.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// One of the agent's profiles to run instead of its shell, see
+    /// [`crate::handshake::Welcome::profiles`].
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// User to run the session as, one of
+    /// [`crate::handshake::Welcome::users`].
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+/// Whether `name` is covered by one of `patterns`, which are variable
+/// names or prefixes ending in `*`, as in `LC_*`.
+pub fn matches<S: AsRef<str>>(patterns: &[S], name: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.as_ref();
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        }
+    })
+}
+
+/// The variables of `env` that are part of [`TERMINAL_ENV`] or match
+/// `extra`.
+pub fn select<I: IntoIterator<Item = (String, String)>, S: AsRef<str>>(
+    env: I,
+    extra: &[S]
+) -> BTreeMap<String, String> {
+    env.into_iter()
+        .filter(|(name, _)| matches(&TERMINAL_ENV, name) || matches(extra, name))
+        .collect()
+}